@@ -0,0 +1,371 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+use crate::SendError;
+
+struct ChannelCtx<T> {
+    // last `capacity` values sent, oldest first
+    ring: VecDeque<T>,
+    // sequence number of ring[0]
+    head: u64,
+    n_senders: u32,
+    n_receivers: u32,
+}
+
+impl<T> ChannelCtx<T> {
+    fn tail(&self) -> u64 {
+        self.head + self.ring.len() as u64
+    }
+}
+
+impl<T: Clone> ChannelCtx<T> {
+    // hands out the value at `next` and advances it
+    fn take(&self, next: &mut u64) -> Result<T, TryRecvError> {
+        if *next < self.head {
+            let missed = self.head - *next;
+            *next = self.head;
+            return Err(TryRecvError::Lagged(missed));
+        }
+        match self.ring.get((*next - self.head) as usize) {
+            Some(value) => {
+                *next += 1;
+                Ok(value.clone())
+            }
+            None if self.n_senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+struct Shared<T> {
+    mu: Mutex<ChannelCtx<T>>,
+    cond: Condvar,
+    capacity: usize,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, ChannelCtx<T>> {
+        self.mu.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Publishes a value to every receiver, evicting the oldest one if the ring is full.
+    ///
+    /// Never blocks, fails only when there are no receivers.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut ctx = self.shared.lock();
+        if ctx.n_receivers == 0 {
+            return Err(SendError(value));
+        }
+        // dropped outside the lock, it may run arbitrary code
+        let mut evicted = None;
+        if ctx.ring.len() == self.shared.capacity {
+            evicted = ctx.ring.pop_front();
+            ctx.head += 1;
+        }
+        ctx.ring.push_back(value);
+        drop(ctx);
+        self.shared.cond.notify_all();
+        drop(evicted);
+        Ok(())
+    }
+
+    /// Creates a receiver that sees every value sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut ctx = self.shared.lock();
+        ctx.n_receivers += 1;
+        let next = ctx.tail();
+        drop(ctx);
+        Receiver {
+            shared: Arc::clone(&self.shared),
+            next,
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        let mut ctx = self.shared.lock();
+        ctx.n_senders += 1;
+        drop(ctx);
+        Self {
+            shared: Arc::clone(&self.shared)
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut ctx = self.shared.lock();
+        ctx.n_senders -= 1;
+        let is_last_samurai = ctx.n_senders == 0;
+        drop(ctx);
+        if is_last_samurai {
+            self.shared.cond.notify_all();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    // sequence number of the next value to hand out
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Blocks until the next value is published.
+    ///
+    /// Returns `Lagged(n)` and skips ahead if `n` values were evicted before this receiver got to them.
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        let mut ctx = self.shared.lock();
+        loop {
+            match ctx.take(&mut self.next) {
+                Err(TryRecvError::Empty) => {
+                    ctx = self.shared.cond.wait(ctx).unwrap_or_else(PoisonError::into_inner);
+                }
+                Err(TryRecvError::Lagged(n)) => return Err(RecvError::Lagged(n)),
+                Err(TryRecvError::Disconnected) => return Err(RecvError::Disconnected),
+                Ok(value) => return Ok(value),
+            }
+        }
+    }
+
+    /// Receives the next value without blocking.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let ctx = self.shared.lock();
+        ctx.take(&mut self.next)
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    // the clone picks up at the same position as this receiver
+    fn clone(&self) -> Self {
+        let mut ctx = self.shared.lock();
+        ctx.n_receivers += 1;
+        drop(ctx);
+        Self {
+            shared: Arc::clone(&self.shared),
+            next: self.next,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut ctx = self.shared.lock();
+        ctx.n_receivers -= 1;
+        if ctx.n_receivers > 0 {
+            return;
+        }
+        // nobody can read these anymore, new subscribers start at the tail
+        ctx.head = ctx.tail();
+        let ring = std::mem::take(&mut ctx.ring);
+        drop(ctx);
+        drop(ring);
+    }
+}
+
+/// Yields every value this receiver gets to, skipping over lagged ones.
+impl<T: Clone> Iterator for Receiver<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.recv() {
+                Ok(value) => return Some(value),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+/// Creates a broadcast channel that keeps the last `capacity` values for slow receivers.
+///
+/// Panics if `capacity` is zero.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "broadcast capacity must be positive");
+    let shared = Arc::new(Shared {
+        mu: Mutex::new(ChannelCtx {
+            ring: VecDeque::with_capacity(capacity),
+            head: 0,
+            n_senders: 1,
+            n_receivers: 1,
+        }),
+        cond: Condvar::new(),
+        capacity,
+    });
+    let s = Sender {
+        shared: Arc::clone(&shared),
+    };
+    let r = Receiver {
+        shared: Arc::clone(&shared),
+        next: 0,
+    };
+    (s, r)
+}
+
+/// Error returned by `Receiver::recv`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RecvError {
+    /// The receiver fell behind and this many values were evicted before it read them.
+    Lagged(u64),
+    /// Nothing left to read and all senders are gone.
+    Disconnected,
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvError::Lagged(n) => write!(f, "receiver lagged behind by {} messages", n),
+            RecvError::Disconnected => f.write_str("receiving on a closed channel"),
+        }
+    }
+}
+
+impl Error for RecvError {}
+
+/// Error returned by `Receiver::try_recv`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TryRecvError {
+    /// Nothing new yet, but senders are still alive.
+    Empty,
+    /// The receiver fell behind and this many values were evicted before it read them.
+    Lagged(u64),
+    /// Nothing left to read and all senders are gone.
+    Disconnected,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+            TryRecvError::Lagged(n) => write!(f, "receiver lagged behind by {} messages", n),
+            TryRecvError::Disconnected => f.write_str("receiving on a closed channel"),
+        }
+    }
+}
+
+impl Error for TryRecvError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    #[test]
+    fn every_receiver_gets_every_message() {
+        let (s, mut r1) = channel(4);
+        let mut r2 = s.subscribe();
+        s.send(21).unwrap();
+        s.send(22).unwrap();
+        assert_eq!(r1.recv(), Ok(21));
+        assert_eq!(r1.recv(), Ok(22));
+        assert_eq!(r2.recv(), Ok(21));
+        assert_eq!(r2.recv(), Ok(22));
+    }
+
+    #[test]
+    fn subscribe_starts_at_tail() {
+        let (s, mut r1) = channel(4);
+        s.send(21).unwrap();
+        let mut r2 = s.subscribe();
+        s.send(22).unwrap();
+        assert_eq!(r1.try_recv(), Ok(21));
+        assert_eq!(r2.try_recv(), Ok(22));
+        assert_eq!(r2.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn lagged() {
+        let (s, mut r) = channel(2);
+        for i in 0..5 {
+            s.send(i).unwrap();
+        }
+        assert_eq!(r.recv(), Err(RecvError::Lagged(3)));
+        assert_eq!(r.recv(), Ok(3));
+        assert_eq!(r.recv(), Ok(4));
+    }
+
+    #[test]
+    fn close_sending() {
+        let (s, mut r) = channel(2);
+        s.send(21).unwrap();
+        drop(s);
+        assert_eq!(r.recv(), Ok(21));
+        assert_eq!(r.recv(), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn close_receiving() {
+        let (s, r) = channel(2);
+        drop(r);
+        assert_eq!(s.send(21), Err(SendError(21)));
+    }
+
+    #[test]
+    fn iterator_skips_lagged() {
+        let (s, r) = channel(2);
+        for i in 0..5 {
+            s.send(i).unwrap();
+        }
+        drop(s);
+        assert_eq!(r.collect::<Vec<_>>(), vec![3, 4]);
+    }
+
+    #[test]
+    fn recv_blocks_until_send() {
+        let (s, r) = channel(2);
+        let threads: Vec<_> = (0..3)
+            .map(|_| {
+                let mut r = r.clone();
+                thread::spawn(move || r.recv())
+            })
+            .collect();
+        drop(r);
+        thread::sleep(Duration::from_millis(50));
+        s.send(21).unwrap();
+        for t in threads {
+            assert_eq!(t.join().unwrap(), Ok(21));
+        }
+    }
+
+    #[test]
+    fn auto_traits() {
+        fn assert_send<T: Send>() {}
+        fn assert_sync<T: Sync>() {}
+        assert_send::<Sender<i32>>();
+        assert_sync::<Sender<i32>>();
+        assert_send::<Receiver<i32>>();
+        assert_sync::<Receiver<i32>>();
+    }
+
+    #[test]
+    fn survives_panicking_clone() {
+        struct Bomb(bool);
+        impl Clone for Bomb {
+            fn clone(&self) -> Self {
+                assert!(!self.0, "bomb went off");
+                Bomb(false)
+            }
+        }
+        let (s, r) = channel(2);
+        s.send(Bomb(true)).unwrap();
+        let mut r2 = s.subscribe();
+        // poisons the lock while cloning under it
+        let t = thread::spawn(move || {
+            let mut r = r;
+            r.recv().ok();
+        });
+        assert!(t.join().is_err());
+        s.send(Bomb(false)).unwrap();
+        assert!(!r2.try_recv().unwrap().0);
+    }
+}
@@ -1,157 +1,2565 @@
-use std::collections::VecDeque;
-use std::sync::{Arc, Condvar, Mutex};
+pub mod broadcast;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod oneshot;
+pub mod watch;
+mod queue;
+mod select;
+#[cfg(feature = "stats")]
+mod stats;
+mod sync;
 
-struct ChannelCtx<T> {
-    queue: VecDeque<T>,
-    n_senders: u32,
+pub use select::{ReadyTimeoutError, Select, TryReadyError};
+#[cfg(feature = "stats")]
+pub use stats::{ChannelStats, WaitHistogram};
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::PoisonError;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::queue::{Pop, Queue};
+use crate::sync::{fence, yield_now, Arc, AtomicBool, AtomicU64, AtomicUsize, Condvar, Mutex, MutexGuard};
+
+// what only threads going to sleep or waking others up touch, values go
+// through Shared::queue without taking the lock
+struct ChannelCtx<E> {
+    // threads blocked on cond and send_cond
+    n_recv_parked: u32,
+    n_send_parked: u32,
+    // send_all calls among n_send_parked, one may need more room than a
+    // single freed slot so it can't be the only sender woken
+    n_batch_parked: u32,
+    // the reason from Sender::close_with
+    close_reason: Option<std::sync::Arc<E>>,
+    // woken alongside cond whenever a receiver may become ready
+    wakers: Vec<Waker>,
+    // woken alongside send_cond
+    send_wakers: Vec<Waker>,
+    #[cfg(feature = "stats")]
+    stats: stats::Recorder,
+}
+
+struct Shared<T, E> {
+    queue: Queue<T>,
+    // values sent and not yet received, senders reserve room here before
+    // pushing so a bounded channel never goes over capacity
+    len: AtomicUsize,
+    n_senders: AtomicUsize,
+    n_receivers: AtomicUsize,
+    // set by either side's close
+    closed: AtomicBool,
+    // values dropped by the overflow policy
+    n_evicted: AtomicU64,
+    // who's blocked or has a waker registered on either side, mirrored from
+    // ChannelCtx so the hot paths only take the lock when someone is waiting
+    recv_watch: AtomicUsize,
+    send_watch: AtomicUsize,
+    #[cfg(feature = "stats")]
+    counters: stats::Counters,
+    mu: Mutex<ChannelCtx<E>>,
+    cond: Condvar,
+    // senders wait here while a bounded channel is at capacity
+    send_cond: Condvar,
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+    on_evict: Option<Box<dyn Fn(T) + Send + Sync>>,
+}
+
+impl<T, E> Shared<T, E> {
+    // every update to ChannelCtx is complete before anything that could panic
+    // runs, so a lock poisoned by a panicking thread is simply taken over
+    // instead of spreading the panic to every other handle. broadcast, oneshot
+    // and watch keep to the same rule for their own locks
+    fn lock(&self) -> MutexGuard<'_, ChannelCtx<E>> {
+        self.mu.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // a closed channel counts as having neither senders nor receivers, except
+    // that what's already queued can still be received
+    fn no_senders(&self) -> bool {
+        self.n_senders.load(Ordering::SeqCst) == 0 || self.closed.load(Ordering::SeqCst)
+    }
+
+    fn no_receivers(&self) -> bool {
+        self.n_receivers.load(Ordering::SeqCst) == 0 || self.closed.load(Ordering::SeqCst)
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    // whether `n` more values fit next to `len`, a batch bigger than the
+    // channel goes in whole once everything before it has been taken
+    fn has_room(&self, len: usize, n: usize) -> bool {
+        match self.capacity {
+            // a rendezvous channel still parks one value in queue for the receiver to take
+            Some(capacity) => len == 0 || len + n <= capacity.max(1),
+            None => true,
+        }
+    }
+
+    // takes room for `n` values, false if they don't fit right now
+    fn try_reserve(&self, n: usize) -> bool {
+        if self.capacity.is_none() {
+            let _len = self.len.fetch_add(n, Ordering::SeqCst);
+            #[cfg(feature = "stats")]
+            self.counters.reached(_len + n);
+            return true;
+        }
+        let mut len = self.len.load(Ordering::SeqCst);
+        loop {
+            if !self.has_room(len, n) {
+                return false;
+            }
+            match self
+                .len
+                .compare_exchange_weak(len, len + n, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => {
+                    #[cfg(feature = "stats")]
+                    self.counters.reached(len + n);
+                    return true;
+                }
+                Err(current) => len = current,
+            }
+        }
+    }
+
+    // like try_reserve, but makes room by evicting the oldest values
+    fn reserve_evicting(&self, n: usize, evicted: &mut Vec<T>) {
+        while !self.try_reserve(n) {
+            match self.pop() {
+                Ok(oldest) => {
+                    self.n_evicted.fetch_add(1, Ordering::Relaxed);
+                    evicted.push(oldest);
+                }
+                // the room is held by values on their way in or out
+                Err(_) => yield_now(),
+            }
+        }
+    }
+
+    // gives back room reserved for values that never made it into queue
+    fn release(&self, n: usize) {
+        self.len.fetch_sub(n, Ordering::SeqCst);
+    }
+
+    // hands values dropped by the overflow policy to the hook
+    fn evicted(&self, values: Vec<T>) {
+        match &self.on_evict {
+            Some(on_evict) => values.into_iter().for_each(on_evict),
+            None => drop(values),
+        }
+    }
+
+    fn pop(&self) -> Result<T, Pop> {
+        let value = self.queue.pop()?;
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        Ok(value)
+    }
+
+    // takes a sender's own values back, see Queue::reclaim
+    fn reclaim(&self, last: usize) -> Option<T> {
+        let value = self.queue.reclaim(last)?;
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        Some(value)
+    }
+
+    // takes back a rendezvous value nobody picked up, freeing its slot for other senders
+    fn take_back(&self, pos: usize) -> Option<T> {
+        let value = self.reclaim(pos)?;
+        #[cfg(feature = "stats")]
+        self.counters.unpushed(1);
+        self.notify_all_senders();
+        Some(value)
+    }
+
+    // whether a receiver is blocked or has a waker registered
+    fn is_recv_waiting(&self) -> bool {
+        let ctx = self.lock();
+        ctx.n_recv_parked > 0 || !ctx.wakers.is_empty()
+    }
+
+    // pops a value for a receiver and wakes whoever waits on the room it leaves
+    fn take(&self) -> Result<T, Pop> {
+        let value = self.pop()?;
+        #[cfg(feature = "stats")]
+        self.counters.taken(1);
+        self.notify_senders();
+        Ok(value)
+    }
+
+    // like take, but up to `limit` values at once
+    fn take_many(&self, out: &mut Vec<T>, limit: usize) -> Result<usize, Pop> {
+        out.push(self.pop()?);
+        let mut n = 1;
+        while n < limit {
+            match self.pop() {
+                Ok(value) => out.push(value),
+                Err(_) => break,
+            }
+            n += 1;
+        }
+        #[cfg(feature = "stats")]
+        self.counters.taken(n);
+        self.notify_all_senders();
+        Ok(n)
+    }
+
+    // publishes who is waiting, the fence pairs with the one in the notify
+    // functions: either the notifier sees us or we see what it did before
+    // we go to sleep
+    fn watch(&self, ctx: &ChannelCtx<E>) {
+        self.recv_watch
+            .store(ctx.n_recv_parked as usize + ctx.wakers.len(), Ordering::SeqCst);
+        self.send_watch
+            .store(ctx.n_send_parked as usize + ctx.send_wakers.len(), Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+    }
+
+    // blocks until a value may have arrived, or `timeout` runs out
+    fn wait_recv(&self, timeout: Option<Duration>) {
+        let mut ctx = self.lock();
+        ctx.n_recv_parked += 1;
+        self.watch(&ctx);
+        // a value sent before we were watched didn't wake anyone
+        if self.queue.is_empty() && !self.queue.is_closed() {
+            #[cfg(feature = "stats")]
+            let started = Instant::now();
+            ctx = match timeout {
+                Some(timeout) => self.cond.wait_timeout(ctx, timeout).unwrap_or_else(PoisonError::into_inner).0,
+                None => self.cond.wait(ctx).unwrap_or_else(PoisonError::into_inner),
+            };
+            #[cfg(feature = "stats")]
+            ctx.stats.recv_wait.record(started.elapsed());
+        }
+        ctx.n_recv_parked -= 1;
+        self.watch(&ctx);
+    }
+
+    // waits on send_cond until `is_ready` may have become true, returns
+    // false without waiting once `wait` doesn't allow it anymore
+    fn wait_send_for(&self, wait: Wait, is_batch: bool, is_ready: impl Fn() -> bool) -> bool {
+        let timeout = match wait {
+            Wait::Forever => None,
+            Wait::Never => return false,
+            Wait::Until(deadline) => {
+                // re-checked on every wakeup, spurious or not
+                let now = Instant::now();
+                if now >= deadline {
+                    return false;
+                }
+                Some(deadline - now)
+            }
+        };
+        let mut ctx = self.lock();
+        ctx.n_send_parked += 1;
+        ctx.n_batch_parked += is_batch as u32;
+        self.watch(&ctx);
+        if !is_ready() {
+            #[cfg(feature = "stats")]
+            let started = Instant::now();
+            ctx = match timeout {
+                Some(timeout) => {
+                    self.send_cond.wait_timeout(ctx, timeout).unwrap_or_else(PoisonError::into_inner).0
+                }
+                None => self.send_cond.wait(ctx).unwrap_or_else(PoisonError::into_inner),
+            };
+            #[cfg(feature = "stats")]
+            ctx.stats.send_wait.record(started.elapsed());
+        }
+        ctx.n_send_parked -= 1;
+        ctx.n_batch_parked -= is_batch as u32;
+        self.watch(&ctx);
+        true
+    }
+
+    // wakes a receiver after a value was pushed, or all of them after a batch
+    fn notify_receivers(&self, all: bool) {
+        fence(Ordering::SeqCst);
+        if self.recv_watch.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut ctx = self.lock();
+        let wakers = std::mem::take(&mut ctx.wakers);
+        let is_parked = ctx.n_recv_parked > 0;
+        self.watch(&ctx);
+        drop(ctx);
+        match is_parked {
+            true if all => self.cond.notify_all(),
+            true => self.cond.notify_one(),
+            false => {}
+        }
+        wake_all(wakers);
+    }
+
+    // wakes whoever waits on capacity after a value was taken
+    fn notify_senders(&self) {
+        fence(Ordering::SeqCst);
+        if self.send_watch.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut ctx = self.lock();
+        let wakers = std::mem::take(&mut ctx.send_wakers);
+        let is_parked = ctx.n_send_parked > 0;
+        let is_batch_parked = ctx.n_batch_parked > 0;
+        self.watch(&ctx);
+        drop(ctx);
+        match self.capacity {
+            // wake the sender waiting for pickup, not just one waiting for the slot
+            Some(0) if is_parked => self.send_cond.notify_all(),
+            Some(_) if is_batch_parked => self.send_cond.notify_all(),
+            Some(_) if is_parked => self.send_cond.notify_one(),
+            _ => {}
+        }
+        wake_all(wakers);
+    }
+
+    // like notify_senders, for when several slots were freed at once
+    fn notify_all_senders(&self) {
+        fence(Ordering::SeqCst);
+        if self.send_watch.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut ctx = self.lock();
+        let wakers = std::mem::take(&mut ctx.send_wakers);
+        let is_parked = ctx.n_send_parked > 0;
+        self.watch(&ctx);
+        drop(ctx);
+        if is_parked {
+            self.send_cond.notify_all();
+        }
+        wake_all(wakers);
+    }
+
+    // async counterpart of Sender::send, `value` is taken once it's queued and
+    // `ticket` holds a rendezvous value's position until a receiver picks it up
+    fn poll_send(
+        &self,
+        cx: &mut Context<'_>,
+        value: &mut Option<T>,
+        ticket: &mut Option<usize>,
+    ) -> Poll<Result<(), SendError<T>>> {
+        if let Some(pos) = *ticket {
+            return self.poll_pickup(cx, pos, ticket);
+        }
+        let mut evicted = Vec::new();
+        let mut is_registered = false;
+        loop {
+            if self.queue.is_closed() {
+                return Poll::Ready(Err(SendError(value.take().unwrap())));
+            }
+            if self.try_reserve(1) {
+                break;
+            }
+            match self.policy {
+                OverflowPolicy::Block if is_registered => return Poll::Pending,
+                OverflowPolicy::Block => {
+                    let mut ctx = self.lock();
+                    register_waker(&mut ctx.send_wakers, cx.waker());
+                    self.watch(&ctx);
+                    // room freed before the waker was visible didn't wake it, go around once more
+                    is_registered = true;
+                }
+                OverflowPolicy::DropOldest => {
+                    self.reserve_evicting(1, &mut evicted);
+                    break;
+                }
+                OverflowPolicy::DropNewest => {
+                    self.n_evicted.fetch_add(1, Ordering::Relaxed);
+                    self.evicted(vec![value.take().unwrap()]);
+                    return Poll::Ready(Ok(()));
+                }
+                OverflowPolicy::Reject => return Poll::Ready(Err(SendError(value.take().unwrap()))),
+            }
+        }
+        let pushed = self.queue.push(value.take().unwrap());
+        self.evicted(evicted);
+        match pushed {
+            Err(value) => {
+                self.release(1);
+                Poll::Ready(Err(SendError(value)))
+            }
+            Ok(pos) => {
+                #[cfg(feature = "stats")]
+                self.counters.pushed(1);
+                self.notify_receivers(false);
+                if self.capacity != Some(0) {
+                    return Poll::Ready(Ok(()));
+                }
+                *ticket = Some(pos);
+                self.poll_pickup(cx, pos, ticket)
+            }
+        }
+    }
+
+    // waits for the rendezvous value at `pos` to be taken
+    fn poll_pickup(
+        &self,
+        cx: &mut Context<'_>,
+        pos: usize,
+        ticket: &mut Option<usize>,
+    ) -> Poll<Result<(), SendError<T>>> {
+        let mut is_registered = false;
+        loop {
+            if self.queue.head_pos() > pos {
+                *ticket = None;
+                return Poll::Ready(Ok(()));
+            }
+            if self.queue.is_closed() {
+                *ticket = None;
+                // the receiver left our value in queue for us to take back
+                return Poll::Ready(match self.reclaim(pos) {
+                    Some(value) => Err(SendError(value)),
+                    None => Ok(()),
+                });
+            }
+            if is_registered {
+                return Poll::Pending;
+            }
+            let mut ctx = self.lock();
+            register_waker(&mut ctx.send_wakers, cx.waker());
+            self.watch(&ctx);
+            is_registered = true;
+        }
+    }
+
+    // closes the channel for both sides and wakes everyone blocked on it,
+    // a reason given after it was already closed is dropped
+    fn close(&self, reason: Option<std::sync::Arc<E>>) {
+        let mut ctx = self.lock();
+        if self.closed.load(Ordering::SeqCst) {
+            return;
+        }
+        // set before the queue closes so anyone who sees the end of the stream sees the reason
+        ctx.close_reason = reason;
+        self.closed.store(true, Ordering::SeqCst);
+        self.queue.close();
+        let mut wakers = std::mem::take(&mut ctx.wakers);
+        wakers.append(&mut ctx.send_wakers);
+        self.watch(&ctx);
+        drop(ctx);
+        self.cond.notify_all();
+        self.send_cond.notify_all();
+        wake_all(wakers);
+    }
+
+    #[cfg(feature = "stats")]
+    fn stats(&self) -> ChannelStats {
+        let ctx = self.lock();
+        ChannelStats {
+            sent: self.counters.sent.load(Ordering::Relaxed),
+            received: self.counters.received.load(Ordering::Relaxed),
+            high_water_mark: self.counters.high_water_mark.load(Ordering::Relaxed),
+            recv_wait: ctx.stats.recv_wait.clone(),
+            send_wait: ctx.stats.send_wait.clone(),
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn sample(&self) -> metrics::Sample {
+        metrics::Sample {
+            depth: self.len(),
+            senders: self.n_senders.load(Ordering::SeqCst) as u32,
+            disconnected: self.no_senders() || self.no_receivers(),
+            stats: self.stats(),
+        }
+    }
+
+    // `is_disconnected` is from the point of view of the handle being printed
+    fn fmt_handle(&self, name: &str, is_disconnected: fn(&Self) -> bool, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct(name)
+            .field("len", &self.len())
+            .field("capacity", &self.capacity)
+            .field("senders", &self.n_senders.load(Ordering::SeqCst))
+            .field("receivers", &self.n_receivers.load(Ordering::SeqCst))
+            .field("disconnected", &is_disconnected(self))
+            .finish()
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<T: Send, E: Send + Sync> metrics::Probe for Shared<T, E> {
+    fn sample(&self) -> metrics::Sample {
+        Shared::sample(self)
+    }
+}
+
+// how long a send may block for room or, on a rendezvous channel, for pickup
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Wait {
+    Forever,
+    Never,
+    Until(Instant),
+}
+
+pub struct Sender<T, E = ()> {
+    shared: Arc<Shared<T, E>>,
+}
+
+impl<T, E> Sender<T, E> {
+    /// Sends a value, giving it back in `SendError` if all receivers are gone.
+    ///
+    /// A full channel is handled according to its `OverflowPolicy`. `Reject` gives
+    /// the value back in `SendError` as well, `try_send` and `send_timeout` tell
+    /// a rejected value apart from a disconnected channel.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.send_with(value, Wait::Forever).map_err(|e| SendError(e.into_inner()))
+    }
+
+    /// Sends a value only if it can be done without blocking.
+    ///
+    /// On a rendezvous channel that means a receiver has to be blocked waiting for it.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.send_with(value, Wait::Never)
+    }
+
+    /// Like `send`, but gives the value back if there's no room for it within `timeout`.
+    ///
+    /// On a rendezvous channel the receiver has to pick the value up within `timeout`.
+    pub fn send_timeout(&self, value: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        let wait = match Instant::now().checked_add(timeout) {
+            Some(deadline) => Wait::Until(deadline),
+            None => Wait::Forever,
+        };
+        self.send_with(value, wait).map_err(|e| match e {
+            TrySendError::Full(value) => SendTimeoutError::Timeout(value),
+            TrySendError::Disconnected(value) => SendTimeoutError::Disconnected(value),
+        })
+    }
+
+    // `Full` once it has waited as long as `wait` allows
+    fn send_with(&self, value: T, wait: Wait) -> Result<(), TrySendError<T>> {
+        let shared = &*self.shared;
+        let mut evicted = Vec::new();
+        loop {
+            if shared.queue.is_closed() {
+                return Err(TrySendError::Disconnected(value));
+            }
+            if shared.try_reserve(1) {
+                break;
+            }
+            match shared.policy {
+                OverflowPolicy::Block => {
+                    let is_ready = || shared.has_room(shared.len(), 1) || shared.queue.is_closed();
+                    if !shared.wait_send_for(wait, false, is_ready) {
+                        return Err(TrySendError::Full(value));
+                    }
+                }
+                OverflowPolicy::DropOldest => {
+                    shared.reserve_evicting(1, &mut evicted);
+                    break;
+                }
+                OverflowPolicy::DropNewest => {
+                    shared.n_evicted.fetch_add(1, Ordering::Relaxed);
+                    shared.evicted(vec![value]);
+                    return Ok(());
+                }
+                OverflowPolicy::Reject => return Err(TrySendError::Full(value)),
+            }
+        }
+        let is_rendezvous = shared.capacity == Some(0);
+        // try_send hands its value to a waiting receiver without waiting for the pickup
+        let is_handoff = is_rendezvous && wait == Wait::Never;
+        if is_handoff && !shared.is_recv_waiting() {
+            shared.release(1);
+            shared.notify_all_senders();
+            return Err(TrySendError::Full(value));
+        }
+        let pos = match shared.queue.push(value) {
+            Ok(pos) => pos,
+            Err(value) => {
+                shared.release(1);
+                shared.evicted(evicted);
+                return Err(TrySendError::Disconnected(value));
+            }
+        };
+        #[cfg(feature = "stats")]
+        shared.counters.pushed(1);
+        // the receiver may have given up between the check and the push
+        if is_handoff && shared.queue.head_pos() <= pos && !shared.is_recv_waiting() {
+            if let Some(value) = shared.take_back(pos) {
+                return Err(TrySendError::Full(value));
+            }
+        }
+        shared.notify_receivers(false);
+        shared.evicted(evicted);
+        if is_rendezvous && !is_handoff {
+            let is_ready = || shared.queue.head_pos() > pos || shared.queue.is_closed();
+            while shared.queue.head_pos() <= pos {
+                if shared.queue.is_closed() {
+                    // the receiver left our value in queue for us to take back
+                    return match shared.reclaim(pos) {
+                        Some(value) => Err(TrySendError::Disconnected(value)),
+                        None => Ok(()),
+                    };
+                }
+                if !shared.wait_send_for(wait, false, is_ready) {
+                    // nothing else can be queued before ours is taken
+                    return match shared.take_back(pos) {
+                        Some(value) => Err(TrySendError::Full(value)),
+                        None => Ok(()),
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends every value from `values` at once.
+    ///
+    /// Receivers see either the whole batch in order or none of it, batches from
+    /// other senders never interleave with it. On a bounded channel this blocks
+    /// until the whole batch fits. Gives the values back if all receivers are gone.
+    ///
+    /// With an `OverflowPolicy` other than `Block` a batch that doesn't fit is
+    /// dropped or rejected as a whole, or makes room by evicting the oldest values.
+    pub fn send_all<I: IntoIterator<Item = T>>(&self, values: I) -> Result<(), SendError<Vec<T>>> {
+        let shared = &*self.shared;
+        let mut values: Vec<T> = values.into_iter().collect();
+        let n = values.len();
+        let mut evicted = Vec::new();
+        loop {
+            if shared.queue.is_closed() {
+                return Err(SendError(values));
+            }
+            if n == 0 {
+                return Ok(());
+            }
+            // DropOldest trims a batch bigger than the channel even when it's empty
+            if shared.policy != OverflowPolicy::DropOldest && shared.try_reserve(n) {
+                break;
+            }
+            match shared.policy {
+                OverflowPolicy::Block => {
+                    let is_ready = || shared.has_room(shared.len(), n) || shared.queue.is_closed();
+                    shared.wait_send_for(Wait::Forever, true, is_ready);
+                }
+                OverflowPolicy::DropOldest => {
+                    // may evict the start of this batch if it's bigger than the channel
+                    let keep = n.min(shared.capacity.unwrap_or(n));
+                    shared.reserve_evicting(keep, &mut evicted);
+                    evicted.extend(values.drain(..n - keep));
+                    shared.n_evicted.fetch_add((n - keep) as u64, Ordering::Relaxed);
+                    break;
+                }
+                OverflowPolicy::DropNewest => {
+                    shared.n_evicted.fetch_add(n as u64, Ordering::Relaxed);
+                    shared.evicted(values);
+                    return Ok(());
+                }
+                OverflowPolicy::Reject => return Err(SendError(values)),
+            }
+        }
+        let pushed = values.len();
+        let last = match shared.queue.push_all(values) {
+            Ok(last) => last,
+            Err(values) => {
+                shared.release(pushed);
+                shared.evicted(evicted);
+                return Err(SendError(values));
+            }
+        };
+        #[cfg(feature = "stats")]
+        shared.counters.pushed(pushed);
+        shared.notify_receivers(true);
+        shared.evicted(evicted);
+        if shared.capacity == Some(0) {
+            let is_ready = || shared.queue.head_pos() > last || shared.queue.is_closed();
+            while shared.queue.head_pos() <= last {
+                if shared.queue.is_closed() {
+                    // whatever wasn't picked up is still ours
+                    let rest: Vec<T> = std::iter::from_fn(|| shared.reclaim(last)).collect();
+                    return match rest.is_empty() {
+                        true => Ok(()),
+                        false => Err(SendError(rest)),
+                    };
+                }
+                shared.wait_send_for(Wait::Forever, false, is_ready);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a value without blocking the thread while the channel is full.
+    pub fn send_async(&self, value: T) -> SendFuture<'_, T, E> {
+        SendFuture {
+            sender: self,
+            value: Some(value),
+            ticket: None,
+        }
+    }
+
+    /// Turns the sender into a `Sink`.
+    #[cfg(feature = "sink")]
+    pub fn into_sink(self) -> SendSink<T, E> {
+        SendSink {
+            sender: self,
+            value: None,
+            ticket: None,
+        }
+    }
+
+    /// Number of values sent and not yet received.
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of live senders, this one included.
+    pub fn sender_count(&self) -> usize {
+        self.shared.n_senders.load(Ordering::SeqCst)
+    }
+
+    /// Whether all receivers are gone or the channel was closed, after which every send fails.
+    pub fn is_disconnected(&self) -> bool {
+        self.shared.no_receivers()
+    }
+
+    /// Number of values the channel's `OverflowPolicy` dropped so far.
+    pub fn evicted_count(&self) -> u64 {
+        self.shared.n_evicted.load(Ordering::Relaxed)
+    }
+
+    /// Creates a sender that doesn't keep the channel open, see `WeakSender`.
+    pub fn downgrade(&self) -> WeakSender<T, E> {
+        WeakSender {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Closes the channel for every sender, further sends fail with `SendError`.
+    ///
+    /// Receivers still get what was already queued before they see the end of the stream.
+    pub fn close(&self) {
+        self.shared.close(None);
+    }
+
+    /// Like `close`, but receivers can get `reason` from `Receiver::recv_with_reason`.
+    ///
+    /// The reason's type is picked when creating the channel, see `channel_with_reason`.
+    pub fn close_with(&self, reason: E) {
+        self.shared.close(Some(std::sync::Arc::new(reason)));
+    }
+
+    /// Snapshot of the channel's counters.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> ChannelStats {
+        self.shared.stats()
+    }
+}
+
+impl<T, E> Clone for Sender<T, E> {
+    fn clone(&self) -> Self {
+        self.shared.n_senders.fetch_add(1, Ordering::SeqCst);
+        Self {
+            shared: Arc::clone(&self.shared)
+        }
+    }
+}
+
+impl<T, E> fmt::Debug for Sender<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.shared.fmt_handle("Sender", Shared::no_receivers, f)
+    }
+}
+
+impl<T, E> Drop for Sender<T, E> {
+    fn drop(&mut self) {
+        let is_last_samurai = self.shared.n_senders.fetch_sub(1, Ordering::SeqCst) == 1;
+        if !is_last_samurai {
+            return;
+        }
+        // every blocked receiver has to see the end of the stream
+        self.shared.queue.close();
+        let mut ctx = self.shared.lock();
+        let wakers = std::mem::take(&mut ctx.wakers);
+        self.shared.watch(&ctx);
+        drop(ctx);
+        self.shared.cond.notify_all();
+        wake_all(wakers);
+    }
+}
+
+/// Sender that doesn't count towards `n_senders`, so receivers still see the
+/// end of the stream once every `Sender` is gone.
+///
+/// It does keep the channel's memory alive, values still queued included.
+pub struct WeakSender<T, E = ()> {
+    shared: Arc<Shared<T, E>>,
+}
+
+impl<T, E> WeakSender<T, E> {
+    /// Gets a `Sender` back, `None` once every strong one has been dropped.
+    pub fn upgrade(&self) -> Option<Sender<T, E>> {
+        let mut n = self.shared.n_senders.load(Ordering::SeqCst);
+        loop {
+            // a channel whose senders are all gone stays closed
+            if n == 0 {
+                return None;
+            }
+            match self
+                .shared
+                .n_senders
+                .compare_exchange_weak(n, n + 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break,
+                Err(current) => n = current,
+            }
+        }
+        Some(Sender {
+            shared: Arc::clone(&self.shared),
+        })
+    }
+}
+
+impl<T, E> Clone for WeakSender<T, E> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T, E> fmt::Debug for WeakSender<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.shared.fmt_handle("WeakSender", Shared::no_receivers, f)
+    }
+}
+
+pub struct Receiver<T, E = ()> {
+    shared: Arc<Shared<T, E>>,
+}
+
+impl<T, E> Receiver<T, E> {
+    pub fn recv(&mut self) -> Option<T> {
+        loop {
+            match self.shared.take() {
+                Ok(value) => return Some(value),
+                Err(Pop::Closed) => return None,
+                Err(Pop::Empty) => self.shared.wait_recv(None),
+            }
+        }
+    }
+
+    /// Receives a value without blocking.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.shared.take().map_err(|e| match e {
+            Pop::Empty => TryRecvError::Empty,
+            Pop::Closed => TryRecvError::Disconnected,
+        })
+    }
+
+    /// Like `recv`, but gives up after `timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.recv_deadline(deadline),
+            None => self.recv().ok_or(RecvTimeoutError::Disconnected),
+        }
+    }
+
+    /// Like `recv`, but gives up once `deadline` has passed.
+    pub fn recv_deadline(&mut self, deadline: Instant) -> Result<T, RecvTimeoutError> {
+        loop {
+            match self.shared.take() {
+                Ok(value) => return Ok(value),
+                Err(Pop::Closed) => return Err(RecvTimeoutError::Disconnected),
+                Err(Pop::Empty) => {
+                    // re-checked on every wakeup, spurious or not
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    self.shared.wait_recv(Some(deadline - now));
+                }
+            }
+        }
+    }
+
+    /// Receives a value without blocking the thread, `None` once all senders are gone.
+    pub fn recv_async(&mut self) -> RecvFuture<'_, T, E> {
+        RecvFuture { receiver: self }
+    }
+
+    /// Polls for the next value, scheduling `cx` to be woken when one may be available.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut is_registered = false;
+        loop {
+            match self.shared.take() {
+                Ok(value) => return Poll::Ready(Some(value)),
+                Err(Pop::Closed) => return Poll::Ready(None),
+                Err(Pop::Empty) if is_registered => return Poll::Pending,
+                Err(Pop::Empty) => {
+                    let mut ctx = self.shared.lock();
+                    register_waker(&mut ctx.wakers, cx.waker());
+                    self.shared.watch(&ctx);
+                    // a value sent before the waker was visible didn't wake it, go around once more
+                    is_registered = true;
+                }
+            }
+        }
+    }
+
+    /// Blocks until at least one value is available, then moves up to `limit` of them into `out`.
+    ///
+    /// Returns how many were received, zero only once all senders are gone (or `limit` is zero).
+    pub fn recv_many(&mut self, out: &mut Vec<T>, limit: usize) -> usize {
+        if limit == 0 {
+            return 0;
+        }
+        loop {
+            match self.shared.take_many(out, limit) {
+                Ok(n) => return n,
+                Err(Pop::Closed) => return 0,
+                Err(Pop::Empty) => self.shared.wait_recv(None),
+            }
+        }
+    }
+
+    /// Moves up to `limit` values that are already queued into `out` without blocking.
+    pub fn try_drain(&mut self, out: &mut Vec<T>, limit: usize) -> Result<usize, TryRecvError> {
+        if limit == 0 {
+            return Ok(0);
+        }
+        self.shared.take_many(out, limit).map_err(|e| match e {
+            Pop::Empty => TryRecvError::Empty,
+            Pop::Closed => TryRecvError::Disconnected,
+        })
+    }
+
+    /// Number of values sent and not yet received.
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of live senders.
+    pub fn sender_count(&self) -> usize {
+        self.shared.n_senders.load(Ordering::SeqCst)
+    }
+
+    /// Whether all senders are gone or the channel was closed, values still queued can be received.
+    pub fn is_disconnected(&self) -> bool {
+        self.shared.no_senders()
+    }
+
+    /// Number of values the channel's `OverflowPolicy` dropped so far.
+    pub fn evicted_count(&self) -> u64 {
+        self.shared.n_evicted.load(Ordering::Relaxed)
+    }
+
+    /// Closes the channel so further sends fail, what's already queued can still be received.
+    pub fn close(&self) {
+        self.shared.close(None);
+    }
+
+    /// Like `recv`, but says why the stream ended instead of returning `None`.
+    pub fn recv_with_reason(&mut self) -> Result<T, Closed<E>> {
+        match self.recv() {
+            Some(value) => Ok(value),
+            // the end of the stream is final, so is the reason
+            None => Err(Closed(self.shared.lock().close_reason.clone())),
+        }
+    }
+
+    /// Snapshot of the channel's counters.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> ChannelStats {
+        self.shared.stats()
+    }
+}
+
+impl<T, E> Clone for Receiver<T, E> {
+    fn clone(&self) -> Self {
+        self.shared.n_receivers.fetch_add(1, Ordering::SeqCst);
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T, E> Drop for Receiver<T, E> {
+    fn drop(&mut self) {
+        if self.shared.n_receivers.fetch_sub(1, Ordering::SeqCst) > 1 {
+            return;
+        }
+        self.shared.queue.close();
+        // nobody is left to take what's queued, but a rendezvous value still
+        // belongs to its blocked sender
+        let mut queue = Vec::new();
+        if self.shared.capacity != Some(0) {
+            while let Ok(value) = self.shared.pop() {
+                queue.push(value);
+            }
+        }
+        let mut ctx = self.shared.lock();
+        let wakers = std::mem::take(&mut ctx.send_wakers);
+        self.shared.watch(&ctx);
+        drop(ctx);
+        self.shared.send_cond.notify_all();
+        wake_all(wakers);
+        drop(queue);
+    }
+}
+
+impl<T, E> fmt::Debug for Receiver<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.shared.fmt_handle("Receiver", Shared::no_senders, f)
+    }
+}
+
+impl<T, E> select::Selectable for Receiver<T, E> {
+    fn is_ready(&self) -> bool {
+        let queue = &self.shared.queue;
+        !queue.is_empty() || queue.is_closed()
+    }
+
+    fn register(&self, waker: &Waker) -> bool {
+        if self.is_ready() {
+            return true;
+        }
+        let mut ctx = self.shared.lock();
+        register_waker(&mut ctx.wakers, waker);
+        self.shared.watch(&ctx);
+        drop(ctx);
+        // a value sent before the waker was visible didn't wake it
+        self.is_ready()
+    }
+
+    fn unregister(&self, waker: &Waker) {
+        let mut ctx = self.shared.lock();
+        ctx.wakers.retain(|w| !w.will_wake(waker));
+        self.shared.watch(&ctx);
+    }
+}
+
+impl<T, E> Iterator for Receiver<T, E> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+    }
+}
+
+#[cfg(feature = "stream")]
+impl<T, E> futures_core::Stream for Receiver<T, E> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+// nothing in Receiver is ever pinned in place
+impl<T, E> Unpin for Receiver<T, E> {}
+
+/// Future returned by `Receiver::recv_async`.
+#[must_use = "futures do nothing unless polled"]
+pub struct RecvFuture<'a, T, E = ()> {
+    receiver: &'a mut Receiver<T, E>,
+}
+
+impl<T, E> Future for RecvFuture<'_, T, E> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
+/// Future returned by `Sender::send_async`.
+#[must_use = "futures do nothing unless polled"]
+pub struct SendFuture<'a, T, E = ()> {
+    sender: &'a Sender<T, E>,
+    value: Option<T>,
+    ticket: Option<usize>,
+}
+
+// the value is only ever moved out, never pinned
+impl<T, E> Unpin for SendFuture<'_, T, E> {}
+
+impl<T, E> Future for SendFuture<'_, T, E> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.sender.shared.poll_send(cx, &mut this.value, &mut this.ticket)
+    }
+}
+
+impl<T, E> Drop for SendFuture<'_, T, E> {
+    fn drop(&mut self) {
+        // a rendezvous value nobody took yet goes with the future
+        if let Some(pos) = self.ticket.take() {
+            self.sender.shared.take_back(pos);
+        }
+    }
+}
+
+/// `Sink` adapter returned by `Sender::into_sink`.
+#[cfg(feature = "sink")]
+pub struct SendSink<T, E = ()> {
+    sender: Sender<T, E>,
+    value: Option<T>,
+    ticket: Option<usize>,
+}
+
+#[cfg(feature = "sink")]
+impl<T, E> SendSink<T, E> {
+    // drives the value handed to start_send until the channel accepts it
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), SendError<T>>> {
+        if self.value.is_none() && self.ticket.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+        self.sender.shared.poll_send(cx, &mut self.value, &mut self.ticket)
+    }
+}
+
+#[cfg(feature = "sink")]
+impl<T, E> Unpin for SendSink<T, E> {}
+
+#[cfg(feature = "sink")]
+impl<T, E> Drop for SendSink<T, E> {
+    fn drop(&mut self) {
+        if let Some(pos) = self.ticket.take() {
+            self.sender.shared.take_back(pos);
+        }
+    }
+}
+
+#[cfg(feature = "sink")]
+impl<T, E> futures_sink::Sink<T> for SendSink<T, E> {
+    type Error = SendError<T>;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        self.get_mut().value = Some(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_pending(cx)
+    }
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    new_channel(None, OverflowPolicy::Block, None)
+}
+
+/// Creates a bounded channel, `send` blocks while `capacity` values are in flight.
+///
+/// With zero capacity `send` blocks until the receiver has taken the value.
+pub fn sync_channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    new_channel(Some(capacity), OverflowPolicy::Block, None)
+}
+
+/// Creates a bounded channel that reacts to being full according to `policy`.
+///
+/// Panics if `capacity` is zero and `policy` isn't `Block`.
+pub fn sync_channel_with<T>(capacity: usize, policy: OverflowPolicy) -> (Sender<T>, Receiver<T>) {
+    new_channel(Some(capacity), policy, None)
+}
+
+/// Like `channel`, but `Sender::close_with` can hand receivers a reason of type `E`.
+pub fn channel_with_reason<T, E>() -> (Sender<T, E>, Receiver<T, E>) {
+    new_channel(None, OverflowPolicy::Block, None)
+}
+
+/// Like `sync_channel`, but `Sender::close_with` can hand receivers a reason of type `E`.
+pub fn sync_channel_with_reason<T, E>(capacity: usize) -> (Sender<T, E>, Receiver<T, E>) {
+    new_channel(Some(capacity), OverflowPolicy::Block, None)
+}
+
+/// Like `sync_channel_with`, but every value the policy drops is handed to `on_evict`.
+///
+/// The hook runs on the sending thread, outside the channel's lock.
+pub fn sync_channel_with_hook<T>(
+    capacity: usize,
+    policy: OverflowPolicy,
+    on_evict: impl Fn(T) + Send + Sync + 'static,
+) -> (Sender<T>, Receiver<T>) {
+    new_channel(Some(capacity), policy, Some(Box::new(on_evict)))
+}
+
+/// Like `channel`, but registered under `name` for `metrics::render_openmetrics`.
+///
+/// Panics if a live channel is already registered under `name`.
+#[cfg(feature = "metrics")]
+pub fn channel_named<T: Send + 'static>(name: &str) -> (Sender<T>, Receiver<T>) {
+    new_channel_named(name, None)
+}
+
+/// Like `sync_channel`, but registered under `name` for `metrics::render_openmetrics`.
+///
+/// Panics if a live channel is already registered under `name`.
+#[cfg(feature = "metrics")]
+pub fn sync_channel_named<T: Send + 'static>(name: &str, capacity: usize) -> (Sender<T>, Receiver<T>) {
+    new_channel_named(name, Some(capacity))
+}
+
+#[cfg(feature = "metrics")]
+fn new_channel_named<T: Send + 'static>(name: &str, capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let (s, r) = new_channel(capacity, OverflowPolicy::Block, None);
+    // the registry only holds a weak reference, it never keeps the channel alive
+    let shared: Arc<dyn metrics::Probe> = s.shared.clone();
+    metrics::register(name, Arc::downgrade(&shared));
+    (s, r)
+}
+
+fn new_channel<T, E>(
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+    on_evict: Option<Box<dyn Fn(T) + Send + Sync>>,
+) -> (Sender<T, E>, Receiver<T, E>) {
+    assert!(
+        capacity != Some(0) || policy == OverflowPolicy::Block,
+        "a rendezvous channel can only block"
+    );
+    let shared = Arc::new(Shared {
+        queue: Queue::new(),
+        len: AtomicUsize::new(0),
+        n_senders: AtomicUsize::new(1),
+        n_receivers: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+        n_evicted: AtomicU64::new(0),
+        recv_watch: AtomicUsize::new(0),
+        send_watch: AtomicUsize::new(0),
+        #[cfg(feature = "stats")]
+        counters: stats::Counters::default(),
+        mu: Mutex::new(ChannelCtx {
+            n_recv_parked: 0,
+            n_send_parked: 0,
+            n_batch_parked: 0,
+            close_reason: None,
+            wakers: Vec::new(),
+            send_wakers: Vec::new(),
+            #[cfg(feature = "stats")]
+            stats: stats::Recorder::default(),
+        }),
+        cond: Condvar::new(),
+        send_cond: Condvar::new(),
+        capacity,
+        policy,
+        on_evict,
+    });
+    let s = Sender {
+        shared: Arc::clone(&shared),
+    };
+    let r = Receiver {
+        shared: Arc::clone(&shared),
+    };
+    (s, r)
+}
+
+fn register_waker(wakers: &mut Vec<Waker>, waker: &Waker) {
+    if !wakers.iter().any(|w| w.will_wake(waker)) {
+        wakers.push(waker.clone());
+    }
+}
+
+fn wake_all(wakers: Vec<Waker>) {
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+/// What sending on a full bounded channel does, see `sync_channel_with`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum OverflowPolicy {
+    /// Wait for room, like `sync_channel`.
+    #[default]
+    Block,
+    /// Evict the oldest queued value to make room.
+    DropOldest,
+    /// Drop the value being sent.
+    DropNewest,
+    /// Give the value being sent back, see `Sender::send`.
+    Reject,
+}
+
+/// Error returned by `Sender::send` when every receiver has been dropped, holds the unsent value.
+///
+/// On a channel with `OverflowPolicy::Reject` it's also returned when the channel is full.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SendError { .. }")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("sending on a closed or full channel")
+    }
+}
+
+impl<T> Error for SendError<T> {}
+
+/// Error returned by `Sender::try_send`, holds the unsent value.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum TrySendError<T> {
+    /// The channel is full, or the overflow policy rejected the value.
+    Full(T),
+    /// All receivers are gone.
+    Disconnected(T),
+}
+
+impl<T> TrySendError<T> {
+    /// Takes back the value that couldn't be sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(value) | TrySendError::Disconnected(value) => value,
+        }
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("sending on a full channel"),
+            TrySendError::Disconnected(_) => f.write_str("sending on a closed channel"),
+        }
+    }
+}
+
+impl<T> Error for TrySendError<T> {}
+
+/// Error returned by `Sender::send_timeout`, holds the unsent value.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum SendTimeoutError<T> {
+    /// No room before the deadline, or the overflow policy rejected the value.
+    Timeout(T),
+    /// All receivers are gone.
+    Disconnected(T),
+}
+
+impl<T> SendTimeoutError<T> {
+    /// Takes back the value that couldn't be sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            SendTimeoutError::Timeout(value) | SendTimeoutError::Disconnected(value) => value,
+        }
+    }
+}
+
+impl<T> fmt::Debug for SendTimeoutError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendTimeoutError::Timeout(_) => f.write_str("Timeout(..)"),
+            SendTimeoutError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for SendTimeoutError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendTimeoutError::Timeout(_) => f.write_str("timed out sending on a full channel"),
+            SendTimeoutError::Disconnected(_) => f.write_str("sending on a closed channel"),
+        }
+    }
+}
+
+impl<T> Error for SendTimeoutError<T> {}
+
+/// Error returned by `Receiver::try_recv`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TryRecvError {
+    /// Nothing queued right now, but senders are still alive.
+    Empty,
+    /// Nothing queued and all senders are gone.
+    Disconnected,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+            TryRecvError::Disconnected => f.write_str("receiving on a closed channel"),
+        }
+    }
+}
+
+impl Error for TryRecvError {}
+
+/// Error returned by `Receiver::recv_with_reason` once the stream has ended.
+pub struct Closed<E = ()>(Option<std::sync::Arc<E>>);
+
+impl<E> Closed<E> {
+    /// The reason given to `Sender::close_with`, if there was one.
+    pub fn reason(&self) -> Option<&E> {
+        self.0.as_deref()
+    }
+}
+
+impl<E> Clone for Closed<E> {
+    fn clone(&self) -> Self {
+        Closed(self.0.clone())
+    }
 }
 
-struct Shared<T> {
-    mu: Mutex<ChannelCtx<T>>,
-    cond: Condvar,
-}
+impl<E> fmt::Debug for Closed<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Closed { .. }")
+    }
+}
+
+impl<E> fmt::Display for Closed<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("receiving on a closed channel")
+    }
+}
+
+impl<E> Error for Closed<E> {}
+
+/// Error returned by `Receiver::recv_timeout` and `Receiver::recv_deadline`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RecvTimeoutError {
+    /// Nothing arrived before the deadline, senders are still alive.
+    Timeout,
+    /// Nothing queued and all senders are gone.
+    Disconnected,
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => f.write_str("timed out waiting on channel"),
+            RecvTimeoutError::Disconnected => f.write_str("receiving on a closed channel"),
+        }
+    }
+}
+
+impl Error for RecvTimeoutError {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::task::Wake;
+    use std::thread;
+
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        struct Unpark(thread::Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = std::pin::pin!(fut);
+        loop {
+            if let Poll::Ready(value) = fut.as_mut().poll(&mut cx) {
+                return value;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn init() {
+        let (_r, _c) = channel::<()>();
+    }
+
+    #[test]
+    fn send() {
+        let (s, _r) = channel();
+        s.send(21).unwrap();
+    }
+
+    #[test]
+    fn recv() {
+        let (s, mut r) = channel();
+        s.send(21).unwrap();
+        assert_eq!(r.recv(), Some(21));
+    }
+
+    #[test]
+    fn multiple_send() {
+        let (s, mut r) = channel();
+        s.send(21).unwrap();
+        s.send(22).unwrap();
+        let sc = s.clone();
+        sc.send(27).unwrap();
+        assert_eq!(r.recv(), Some(21));
+        assert_eq!(r.recv(), Some(22));
+        assert_eq!(r.recv(), Some(27));
+    }
+
+    #[test]
+    fn close_sending() {
+        // should also close/drop recv
+        let (s, mut r) = channel();
+        s.send(21).unwrap();
+        drop(s);
+        assert_eq!(r.recv(), Some(21));
+        assert_eq!(r.recv(), None);
+    }
+
+    #[test]
+    fn close_receiving() {
+        let (s, r) = channel();
+        drop(r);
+        assert_eq!(s.send(21), Err(SendError(21)));
+    }
+
+    #[test]
+    fn close_receiving_frees_queue() {
+        let (s, r) = channel();
+        let value = Arc::new(21);
+        s.send(Arc::clone(&value)).unwrap();
+        drop(r);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn close_receiving_wakes_sync_sender() {
+        let (s, r) = sync_channel(1);
+        s.send(21).unwrap();
+        let t = thread::spawn(move || s.send(22));
+        thread::sleep(Duration::from_millis(50));
+        drop(r);
+        assert_eq!(t.join().unwrap(), Err(SendError(22)));
+    }
+
+    #[test]
+    fn close_receiving_returns_rendezvous_value() {
+        let (s, r) = sync_channel(0);
+        let t = thread::spawn(move || s.send(21));
+        thread::sleep(Duration::from_millis(50));
+        drop(r);
+        assert_eq!(t.join().unwrap(), Err(SendError(21)));
+    }
+
+    #[test]
+    fn sync_send_blocks_when_full() {
+        let (s, mut r) = sync_channel(1);
+        s.send(21).unwrap();
+        let t = thread::spawn(move || s.send(22).unwrap());
+        thread::sleep(Duration::from_millis(50));
+        assert!(!t.is_finished());
+        assert_eq!(r.recv(), Some(21));
+        t.join().unwrap();
+        assert_eq!(r.recv(), Some(22));
+    }
+
+    #[test]
+    fn sync_recv_frees_one_slot() {
+        let (s, mut r) = sync_channel(3);
+        s.send(1).unwrap();
+        s.send(2).unwrap();
+        s.send(3).unwrap();
+        assert_eq!(r.recv(), Some(1));
+        s.send(4).unwrap();
+        let t = thread::spawn(move || s.send(5).unwrap());
+        thread::sleep(Duration::from_millis(50));
+        assert!(!t.is_finished());
+        assert_eq!(r.recv(), Some(2));
+        assert_eq!(r.recv(), Some(3));
+        t.join().unwrap();
+        assert_eq!(r.recv(), Some(4));
+        assert_eq!(r.recv(), Some(5));
+    }
+
+    #[test]
+    fn rendezvous_send_waits_for_recv() {
+        let (s, mut r) = sync_channel(0);
+        let t = thread::spawn(move || {
+            s.send(21).unwrap();
+            s.send(22).unwrap();
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!t.is_finished());
+        assert_eq!(r.recv(), Some(21));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!t.is_finished());
+        assert_eq!(r.recv(), Some(22));
+        t.join().unwrap();
+        assert_eq!(r.recv(), None);
+    }
+
+    #[test]
+    fn rendezvous_multiple_senders() {
+        let (s, mut r) = sync_channel(0);
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let s = s.clone();
+                thread::spawn(move || s.send(i).unwrap())
+            })
+            .collect();
+        drop(s);
+        let mut got: Vec<_> = (&mut r).collect();
+        got.sort();
+        assert_eq!(got, vec![0, 1, 2, 3]);
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn try_recv() {
+        let (s, mut r) = channel();
+        assert_eq!(r.try_recv(), Err(TryRecvError::Empty));
+        s.send(21).unwrap();
+        s.send(22).unwrap();
+        assert_eq!(r.try_recv(), Ok(21));
+        drop(s);
+        // still delivered after disconnect
+        assert_eq!(r.try_recv(), Ok(22));
+        assert_eq!(r.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn recv_timeout() {
+        let (s, mut r) = channel();
+        let start = Instant::now();
+        assert_eq!(r.recv_timeout(Duration::from_millis(50)), Err(RecvTimeoutError::Timeout));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            s.send(21).unwrap();
+        });
+        assert_eq!(r.recv_timeout(Duration::from_secs(10)), Ok(21));
+        t.join().unwrap();
+        assert_eq!(r.recv_timeout(Duration::from_secs(10)), Err(RecvTimeoutError::Disconnected));
+    }
+
+    #[test]
+    fn recv_deadline_in_past() {
+        let (s, mut r) = channel();
+        s.send(21).unwrap();
+        assert_eq!(r.recv_deadline(Instant::now()), Ok(21));
+        assert_eq!(r.recv_deadline(Instant::now()), Err(RecvTimeoutError::Timeout));
+    }
 
-pub struct Sender<T> {
-    shared: Arc<Shared<T>>,
-}
+    #[test]
+    fn multiple_receivers() {
+        let (s, r) = channel();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let r = r.clone();
+                thread::spawn(move || r.sum::<u32>())
+            })
+            .collect();
+        drop(r);
+        for i in 1..=100 {
+            s.send(i).unwrap();
+        }
+        drop(s);
+        let total: u32 = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert_eq!(total, 5050);
+    }
 
-impl<T> Sender<T> {
-    pub fn send(&mut self, value: T) {
-        let mut ctx = self.shared.mu.lock().unwrap();
-        ctx.queue.push_back(value);
-        drop(ctx);
-        self.shared.cond.notify_one();
+    #[test]
+    fn close_sending_wakes_all_receivers() {
+        let (s, r) = channel::<()>();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let mut r = r.clone();
+                thread::spawn(move || r.recv())
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(50));
+        drop(s);
+        for t in threads {
+            assert_eq!(t.join().unwrap(), None);
+        }
     }
-}
 
-impl<T> Clone for Sender<T> {
-    fn clone(&self) -> Self {
-        let mut ctx = self.shared.mu.lock().unwrap();
-        ctx.n_senders += 1;
-        drop(ctx);
-        Self {
-            shared: Arc::clone(&self.shared)
+    #[test]
+    fn cloned_receivers_share_queue() {
+        let (s, mut r) = channel();
+        let mut rc = r.clone();
+        s.send(21).unwrap();
+        s.send(22).unwrap();
+        assert_eq!(r.recv(), Some(21));
+        assert_eq!(rc.try_recv(), Ok(22));
+    }
+
+    #[test]
+    fn dropped_receiver_leaves_queue() {
+        let (s, mut r) = channel();
+        s.send(21).unwrap();
+        s.send(22).unwrap();
+        s.send(23).unwrap();
+        assert_eq!(r.recv(), Some(21));
+        let mut rc = r.clone();
+        drop(r);
+        assert_eq!(rc.try_recv(), Ok(22));
+        assert_eq!(rc.try_recv(), Ok(23));
+        drop(s);
+        assert_eq!(rc.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn recv_async() {
+        let (s, mut r) = channel();
+        s.send(21).unwrap();
+        assert_eq!(block_on(r.recv_async()), Some(21));
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            s.send(22).unwrap();
+        });
+        assert_eq!(block_on(r.recv_async()), Some(22));
+        t.join().unwrap();
+        assert_eq!(block_on(r.recv_async()), None);
+    }
+
+    #[test]
+    fn poll_recv_pending_until_send() {
+        let (s, mut r) = channel();
+        let mut cx = Context::from_waker(Waker::noop());
+        assert_eq!(r.poll_recv(&mut cx), Poll::Pending);
+        assert_eq!(r.shared.mu.lock().unwrap().wakers.len(), 1);
+        s.send(21).unwrap();
+        assert!(r.shared.mu.lock().unwrap().wakers.is_empty());
+        assert_eq!(r.poll_recv(&mut cx), Poll::Ready(Some(21)));
+    }
+
+    #[test]
+    fn recv_async_frees_capacity_for_sync_sender() {
+        let (s, mut r) = sync_channel(1);
+        s.send(21).unwrap();
+        let t = thread::spawn(move || s.send(22).unwrap());
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(block_on(r.recv_async()), Some(21));
+        t.join().unwrap();
+        assert_eq!(block_on(r.recv_async()), Some(22));
+    }
+
+    #[cfg(feature = "stream")]
+    #[test]
+    fn stream() {
+        use futures_core::Stream;
+        let (s, mut r) = channel();
+        s.send(21).unwrap();
+        drop(s);
+        let mut cx = Context::from_waker(Waker::noop());
+        assert_eq!(Pin::new(&mut r).poll_next(&mut cx), Poll::Ready(Some(21)));
+        assert_eq!(Pin::new(&mut r).poll_next(&mut cx), Poll::Ready(None));
+    }
+
+    #[test]
+    fn send_async_waits_for_capacity() {
+        let (s, mut r) = sync_channel(1);
+        block_on(s.send_async(21)).unwrap();
+        let t = thread::spawn(move || {
+            block_on(s.send_async(22)).unwrap();
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!t.is_finished());
+        assert_eq!(r.recv(), Some(21));
+        t.join().unwrap();
+        assert_eq!(r.recv(), Some(22));
+        assert_eq!(r.recv(), None);
+    }
+
+    #[test]
+    fn send_async_rendezvous() {
+        let (s, mut r) = sync_channel(0);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut fut = s.send_async(21);
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Pending);
+        assert_eq!(r.try_recv(), Ok(21));
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Ready(Ok(())));
+    }
+
+    #[test]
+    fn send_async_to_async_receiver() {
+        let (s, mut r) = sync_channel(0);
+        let t = thread::spawn(move || block_on(r.recv_async()));
+        block_on(s.send_async(21)).unwrap();
+        assert_eq!(t.join().unwrap(), Some(21));
+    }
+
+    #[test]
+    fn send_async_close_receiving() {
+        let (s, r) = sync_channel(0);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut fut = s.send_async(21);
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Pending);
+        drop(r);
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Ready(Err(SendError(21))));
+    }
+
+    #[test]
+    fn send_async_rendezvous_dropped() {
+        let (s, mut r) = sync_channel(0);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut fut = s.send_async(21);
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Pending);
+        drop(fut);
+        assert_eq!(r.try_recv(), Err(TryRecvError::Empty));
+        // the slot is free for the next sender
+        let mut fut = s.send_async(22);
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Pending);
+        assert_eq!(r.try_recv(), Ok(22));
+    }
+
+    #[cfg(feature = "sink")]
+    #[test]
+    fn sink() {
+        use futures_sink::Sink;
+        let (s, mut r) = sync_channel(1);
+        let mut sink = s.into_sink();
+        let mut cx = Context::from_waker(Waker::noop());
+        assert_eq!(Pin::new(&mut sink).poll_ready(&mut cx), Poll::Ready(Ok(())));
+        Pin::new(&mut sink).start_send(21).unwrap();
+        assert_eq!(Pin::new(&mut sink).poll_ready(&mut cx), Poll::Ready(Ok(())));
+        Pin::new(&mut sink).start_send(22).unwrap();
+        assert_eq!(Pin::new(&mut sink).poll_flush(&mut cx), Poll::Pending);
+        assert_eq!(r.recv(), Some(21));
+        assert_eq!(Pin::new(&mut sink).poll_flush(&mut cx), Poll::Ready(Ok(())));
+        assert_eq!(r.recv(), Some(22));
+    }
+
+    #[test]
+    fn parked_counts() {
+        let (s, mut r) = sync_channel(1);
+        assert_eq!(r.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+        s.send(21).unwrap();
+        let t = thread::spawn(move || s.send(22).unwrap());
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(r.shared.mu.lock().unwrap().n_send_parked, 1);
+        assert_eq!(r.recv(), Some(21));
+        t.join().unwrap();
+        let ctx = r.shared.mu.lock().unwrap();
+        assert_eq!(ctx.n_recv_parked, 0);
+        assert_eq!(ctx.n_send_parked, 0);
+        assert_eq!(r.shared.send_watch.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn auto_traits() {
+        fn assert_send<T: Send>() {}
+        fn assert_sync<T: Sync>() {}
+        assert_send::<Sender<i32>>();
+        assert_sync::<Sender<i32>>();
+        assert_send::<Receiver<i32>>();
+        assert_sync::<Receiver<i32>>();
+        assert_send::<WeakSender<i32>>();
+        assert_sync::<WeakSender<i32>>();
+        // a shared sender only ever moves values into the queue, never lends them out
+        assert_sync::<Sender<std::cell::Cell<i32>>>();
+        assert_send::<SendFuture<'static, i32>>();
+        assert_send::<RecvFuture<'static, i32>>();
+    }
+
+    #[test]
+    fn shared_sender() {
+        let (s, r) = channel();
+        let s = Arc::new(s);
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let s = Arc::clone(&s);
+                thread::spawn(move || s.send(i).unwrap())
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
         }
+        drop(s);
+        let mut got: Vec<_> = r.collect();
+        got.sort();
+        assert_eq!(got, vec![0, 1, 2, 3]);
     }
-}
 
-impl<T> Drop for Sender<T> {
-    fn drop(&mut self) {
-        let mut ctx = self.shared.mu.lock().unwrap();
-        ctx.n_senders -= 1;
-        let is_last_samurai = ctx.n_senders == 0;
-        drop(ctx);
-        if is_last_samurai {
-            self.shared.cond.notify_one();
+    #[test]
+    fn recv_many() {
+        let (s, mut r) = channel();
+        for i in 0..5 {
+            s.send(i).unwrap();
         }
+        let mut out = Vec::new();
+        assert_eq!(r.recv_many(&mut out, 3), 3);
+        assert_eq!(out, vec![0, 1, 2]);
+        assert_eq!(r.recv_many(&mut out, 3), 2);
+        assert_eq!(out, vec![0, 1, 2, 3, 4]);
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            s.send(5).unwrap();
+        });
+        out.clear();
+        assert_eq!(r.recv_many(&mut out, 3), 1);
+        assert_eq!(out, vec![5]);
+        t.join().unwrap();
+        assert_eq!(r.recv_many(&mut out, 3), 0);
     }
-}
 
-pub struct Receiver<T> {
-    shared: Arc<Shared<T>>,
-    buff: VecDeque<T>,
-}
+    #[test]
+    fn try_drain() {
+        let (s, mut r) = channel();
+        let mut out = Vec::new();
+        assert_eq!(r.try_drain(&mut out, 10), Err(TryRecvError::Empty));
+        s.send(21).unwrap();
+        s.send(22).unwrap();
+        assert_eq!(r.recv(), Some(21));
+        s.send(23).unwrap();
+        assert_eq!(r.try_drain(&mut out, 1), Ok(1));
+        assert_eq!(r.try_drain(&mut out, 10), Ok(1));
+        assert_eq!(out, vec![22, 23]);
+        drop(s);
+        assert_eq!(r.try_drain(&mut out, 10), Err(TryRecvError::Disconnected));
+    }
 
-impl<T> Receiver<T> {
-    pub fn recv(&mut self) -> Option<T> {
-        if let Some(value) = self.buff.pop_front() {
-            return Some(value);
+    #[test]
+    fn try_drain_multiple_receivers() {
+        let (s, mut r) = channel();
+        let _rc = r.clone();
+        for i in 0..5 {
+            s.send(i).unwrap();
         }
+        let mut out = Vec::new();
+        assert_eq!(r.try_drain(&mut out, 2), Ok(2));
+        assert_eq!(r.len(), 3);
+    }
 
-        let mut ctx = self.shared.mu.lock().unwrap();
-        loop {
-            match ctx.queue.pop_front() {
-                None => {
-                    if ctx.n_senders == 0 {
-                        return None;
-                    }
-                    ctx = self.shared.cond.wait(ctx).unwrap();
-                }
-                Some(value) => {
-                    if !ctx.queue.is_empty() {
-                        std::mem::swap(&mut ctx.queue, &mut self.buff);
+    #[test]
+    fn recv_many_frees_capacity() {
+        let (s, mut r) = sync_channel(2);
+        s.send(1).unwrap();
+        s.send(2).unwrap();
+        let t = thread::spawn(move || {
+            s.send(3).unwrap();
+            s.send(4).unwrap();
+        });
+        let mut out = Vec::new();
+        while r.recv_many(&mut out, 1) > 0 {}
+        t.join().unwrap();
+        assert_eq!(out, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn send_all() {
+        let (s, mut r) = channel();
+        s.send_all(vec![21, 22, 23]).unwrap();
+        s.send_all(Vec::new()).unwrap();
+        assert_eq!(r.recv(), Some(21));
+        assert_eq!(r.recv(), Some(22));
+        assert_eq!(r.recv(), Some(23));
+        drop(r);
+        assert_eq!(s.send_all(1..3), Err(SendError(vec![1, 2])));
+    }
+
+    #[test]
+    fn send_all_does_not_interleave() {
+        let (s, r) = channel();
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let s = s.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        s.send_all((0..10).map(|j| i * 10 + j)).unwrap();
                     }
-                    return Some(value);
-                }
-            }
+                })
+            })
+            .collect();
+        drop(s);
+        let got: Vec<_> = r.collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(got.len(), 4000);
+        for batch in got.chunks(10) {
+            let first = batch[0];
+            assert_eq!(first % 10, 0);
+            assert_eq!(batch, (first..first + 10).collect::<Vec<_>>());
         }
     }
-}
 
-impl<T> Iterator for Receiver<T> {
-    type Item = T;
+    #[test]
+    fn send_all_waits_for_room() {
+        let (s, mut r) = sync_channel(3);
+        s.send(1).unwrap();
+        let t = thread::spawn(move || {
+            s.send_all(vec![2, 3, 4]).unwrap();
+            // bigger than the channel, goes in once it's drained
+            s.send_all(vec![5, 6, 7, 8]).unwrap();
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(r.len(), 1);
+        let got: Vec<_> = (&mut r).collect();
+        t.join().unwrap();
+        assert_eq!(got, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.recv()
+    #[test]
+    fn send_all_waiter_does_not_swallow_wakeup() {
+        let (s, mut r) = sync_channel(2);
+        // a second receiver keeps everything in queue
+        let _r2 = r.clone();
+        s.send(1).unwrap();
+        s.send(2).unwrap();
+        let sb = s.clone();
+        let batch = thread::spawn(move || sb.send_all(vec![10, 11]));
+        thread::sleep(Duration::from_millis(50));
+        let single = thread::spawn(move || s.send(20));
+        thread::sleep(Duration::from_millis(50));
+        // frees room for 20 but not for the batch
+        assert_eq!(r.recv(), Some(1));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !single.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(single.is_finished(), "single sender never woke up");
+        assert_eq!(single.join().unwrap(), Ok(()));
+        let got: Vec<_> = (0..4).map(|_| r.recv().unwrap()).collect();
+        assert_eq!(got, vec![2, 20, 10, 11]);
+        assert_eq!(batch.join().unwrap(), Ok(()));
     }
-}
 
-pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
-    let shared = Arc::new(Shared {
-        mu: Mutex::new(ChannelCtx {
-            queue: VecDeque::new(),
-            n_senders: 1,
-        }),
-        cond: Condvar::new()
-    });
-    let s = Sender {
-        shared: Arc::clone(&shared),
-    };
-    let r = Receiver {
-        shared: Arc::clone(&shared),
-        buff: VecDeque::new(),
-    };
-    (s, r)
-}
+    #[test]
+    fn send_all_rendezvous() {
+        let (s, mut r) = sync_channel(0);
+        let t = thread::spawn(move || s.send_all(vec![21, 22]));
+        assert_eq!(r.recv(), Some(21));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!t.is_finished());
+        assert_eq!(r.recv(), Some(22));
+        assert_eq!(t.join().unwrap(), Ok(()));
+    }
+
+    fn poison<T: Send + 'static>(shared: &Arc<Shared<T, ()>>) {
+        let shared = Arc::clone(shared);
+        let t = thread::spawn(move || {
+            let _ctx = shared.mu.lock().unwrap();
+            panic!("holder thread panicked");
+        });
+        assert!(t.join().is_err());
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
     #[test]
-    fn init() {
-        let (_r, _c) = channel::<()>();
+    fn survives_poisoned_lock() {
+        let (s, mut r) = sync_channel(3);
+        s.send(21).unwrap();
+        poison(&s.shared);
+        assert!(s.shared.mu.is_poisoned());
+        s.send(22).unwrap();
+        s.send_all(vec![23]).unwrap();
+        assert_eq!(r.recv(), Some(21));
+        assert_eq!(r.try_recv(), Ok(22));
+        assert_eq!(r.recv_timeout(Duration::from_millis(10)), Ok(23));
+        let sc = s.clone();
+        drop(s);
+        drop(sc);
+        assert_eq!(r.recv(), None);
     }
 
     #[test]
-    fn send() {
-        let (mut s, _r) = channel();
-        s.send(21);
+    fn blocked_receiver_survives_poisoned_lock() {
+        let (s, mut r) = channel();
+        let shared = Arc::clone(&s.shared);
+        let t = thread::spawn(move || r.recv());
+        thread::sleep(Duration::from_millis(50));
+        poison(&shared);
+        s.send(21).unwrap();
+        assert_eq!(t.join().unwrap(), Some(21));
     }
 
     #[test]
-    fn recv() {
-        let (mut s, mut r) = channel();
-        s.send(21);
+    fn len_counts_queued() {
+        let (s, mut r) = channel();
+        assert!(s.is_empty());
+        s.send_all(vec![21, 22, 23]).unwrap();
         assert_eq!(r.recv(), Some(21));
+        assert_eq!(s.len(), 2);
+        assert_eq!(r.len(), 2);
+        r.recv();
+        r.recv();
+        assert!(r.is_empty());
+
+        s.send_all(vec![24, 25, 26]).unwrap();
+        assert_eq!(r.recv(), Some(24));
+        // what's left goes away with the last receiver
+        drop(r);
+        assert_eq!(s.len(), 0);
     }
 
     #[test]
-    fn multiple_send() {
-        let (mut s, mut r) = channel();
-        s.send(21);
-        s.send(22);
-        let mut sc = s.clone();
-        sc.send(27);
+    fn counts_and_disconnect() {
+        let (s, r) = channel::<i32>();
+        let sc = s.clone();
+        assert_eq!(r.sender_count(), 2);
+        assert!(!r.is_disconnected());
+        drop(s);
+        drop(sc);
+        assert_eq!(r.sender_count(), 0);
+        assert!(r.is_disconnected());
+
+        let (s, r) = channel::<i32>();
+        assert!(!s.is_disconnected());
+        drop(r);
+        assert!(s.is_disconnected());
+    }
+
+    #[test]
+    fn debug() {
+        let (s, r) = sync_channel(2);
+        s.send(21).unwrap();
+        assert_eq!(
+            format!("{:?}", s),
+            "Sender { len: 1, capacity: Some(2), senders: 1, receivers: 1, disconnected: false }"
+        );
+        assert_eq!(
+            format!("{:?}", r),
+            "Receiver { len: 1, capacity: Some(2), senders: 1, receivers: 1, disconnected: false }"
+        );
+        s.close();
+        assert_eq!(
+            format!("{:?}", r),
+            "Receiver { len: 1, capacity: Some(2), senders: 1, receivers: 1, disconnected: true }"
+        );
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let hook = Arc::clone(&evicted);
+        let (s, mut r) = sync_channel_with_hook(2, OverflowPolicy::DropOldest, move |v| {
+            hook.lock().unwrap().push(v)
+        });
+        for i in 0..4 {
+            s.send(i).unwrap();
+        }
+        s.send_all(vec![4, 5, 6]).unwrap();
+        assert_eq!(s.evicted_count(), 5);
+        assert_eq!(*evicted.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(r.try_drain(&mut Vec::new(), 8), Ok(2));
+    }
+
+    #[test]
+    fn drop_oldest_keeps_newest() {
+        let (s, mut r) = sync_channel_with(2, OverflowPolicy::DropOldest);
+        s.send(21).unwrap();
+        s.send(22).unwrap();
+        assert_eq!(r.recv(), Some(21));
+        s.send(23).unwrap();
+        s.send(24).unwrap();
+        assert_eq!(r.recv(), Some(23));
+        assert_eq!(r.recv(), Some(24));
+        assert_eq!(r.evicted_count(), 1);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let (s, mut r) = sync_channel_with(2, OverflowPolicy::DropNewest);
+        for i in 0..4 {
+            s.send(i).unwrap();
+        }
+        s.send_all(vec![4, 5]).unwrap();
+        assert_eq!(block_on(s.send_async(6)), Ok(()));
+        assert_eq!(s.evicted_count(), 5);
+        assert_eq!(r.recv(), Some(0));
+        assert_eq!(r.recv(), Some(1));
+        assert_eq!(r.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn reject() {
+        let (s, mut r) = sync_channel_with(1, OverflowPolicy::Reject);
+        s.send(21).unwrap();
+        assert_eq!(s.send(22), Err(SendError(22)));
+        assert_eq!(s.send_all(vec![23]), Err(SendError(vec![23])));
+        assert_eq!(block_on(s.send_async(24)), Err(SendError(24)));
+        // only try_send and send_timeout tell a rejection from a disconnect
+        assert_eq!(s.try_send(22), Err(TrySendError::Full(22)));
+        assert_eq!(s.send_timeout(22, Duration::from_secs(60)), Err(SendTimeoutError::Timeout(22)));
+        assert!(!s.is_disconnected());
+        assert_eq!(s.evicted_count(), 0);
+        assert_eq!(r.recv(), Some(21));
+        s.send(25).unwrap();
+        assert_eq!(r.recv(), Some(25));
+    }
+
+    #[test]
+    #[should_panic(expected = "a rendezvous channel can only block")]
+    fn rendezvous_policy() {
+        sync_channel_with::<i32>(0, OverflowPolicy::DropOldest);
+    }
+
+    #[test]
+    fn try_send() {
+        let (s, mut r) = sync_channel(1);
+        assert_eq!(s.try_send(21), Ok(()));
+        assert_eq!(s.try_send(22), Err(TrySendError::Full(22)));
         assert_eq!(r.recv(), Some(21));
+        assert_eq!(s.try_send(23), Ok(()));
+        drop(r);
+        assert_eq!(s.try_send(24), Err(TrySendError::Disconnected(24)));
+    }
+
+    #[test]
+    fn try_send_rendezvous() {
+        let (s, mut r) = sync_channel(0);
+        assert_eq!(s.try_send(21), Err(TrySendError::Full(21)));
+        let t = thread::spawn(move || r.recv());
+        while s.shared.lock().n_recv_parked == 0 {
+            thread::yield_now();
+        }
+        assert_eq!(s.try_send(22), Ok(()));
+        assert_eq!(t.join().unwrap(), Some(22));
+    }
+
+    #[test]
+    fn try_send_rendezvous_async() {
+        let (s, mut r) = sync_channel(0);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut fut = r.recv_async();
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Pending);
+        assert_eq!(s.try_send(21), Ok(()));
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Ready(Some(21)));
+        // nobody is waiting anymore
+        assert_eq!(s.try_send(22), Err(TrySendError::Full(22)));
+        assert_eq!(r.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(r.len(), 0);
+    }
+
+    #[test]
+    fn send_timeout() {
+        let (s, mut r) = sync_channel(1);
+        s.send(21).unwrap();
+        let start = Instant::now();
+        assert_eq!(s.send_timeout(22, Duration::from_millis(50)), Err(SendTimeoutError::Timeout(22)));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(r.recv(), Some(21));
+            r
+        });
+        assert_eq!(s.send_timeout(23, Duration::from_secs(10)), Ok(()));
+        drop(t.join().unwrap());
+        assert_eq!(s.send_timeout(24, Duration::from_millis(10)), Err(SendTimeoutError::Disconnected(24)));
+    }
+
+    #[test]
+    fn send_timeout_rendezvous() {
+        let (s, mut r) = sync_channel(0);
+        assert_eq!(s.send_timeout(21, Duration::from_millis(50)), Err(SendTimeoutError::Timeout(21)));
+        assert!(r.is_empty());
+        let t = thread::spawn(move || s.send_timeout(22, Duration::from_secs(10)));
         assert_eq!(r.recv(), Some(22));
-        assert_eq!(r.recv(), Some(27));
+        assert_eq!(t.join().unwrap(), Ok(()));
     }
 
     #[test]
-    fn close_sending() {
-        // should also close/drop recv
-        let (mut s, mut r) = channel();
-        s.send(21);
+    fn send_timeout_rendezvous_wakes_next_sender() {
+        let (s, mut r) = sync_channel(0);
+        let s2 = s.clone();
+        let t1 = thread::spawn(move || s.send_timeout(21, Duration::from_millis(100)));
+        thread::sleep(Duration::from_millis(20));
+        // parked behind 21 until it times out
+        let t2 = thread::spawn(move || s2.send(22));
+        assert_eq!(t1.join().unwrap(), Err(SendTimeoutError::Timeout(21)));
+        assert_eq!(r.recv_timeout(Duration::from_secs(10)), Ok(22));
+        assert_eq!(t2.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn weak_sender() {
+        let (s, mut r) = channel();
+        let weak = s.downgrade();
+        let weak2 = weak.clone();
+        assert_eq!(r.sender_count(), 1);
+        let upgraded = weak.upgrade().unwrap();
+        assert_eq!(r.sender_count(), 2);
+        upgraded.send(21).unwrap();
+        drop(upgraded);
         drop(s);
         assert_eq!(r.recv(), Some(21));
         assert_eq!(r.recv(), None);
+        assert!(weak.upgrade().is_none());
+        assert!(weak2.upgrade().is_none());
     }
 
     #[test]
-    fn close_receiving() {
-        let (mut s, r) = channel();
+    fn weak_sender_does_not_block_disconnect() {
+        let (s, mut r) = channel::<i32>();
+        let weak = s.downgrade();
+        let t = thread::spawn(move || r.recv());
+        thread::sleep(Duration::from_millis(50));
+        drop(s);
+        assert_eq!(t.join().unwrap(), None);
+        drop(weak);
+    }
+
+    #[test]
+    fn sender_close() {
+        let (s, mut r) = channel();
+        let sc = s.clone();
+        s.send(21).unwrap();
+        s.close();
+        assert_eq!(sc.send(22), Err(SendError(22)));
+        assert!(sc.is_disconnected());
+        assert!(r.is_disconnected());
+        assert_eq!(r.recv(), Some(21));
+        assert_eq!(r.recv(), None);
+        assert_eq!(r.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn receiver_close() {
+        let (s, mut r) = channel();
+        s.send(21).unwrap();
+        r.close();
+        assert_eq!(s.send(22), Err(SendError(22)));
+        assert_eq!(s.try_send(23), Err(TrySendError::Disconnected(23)));
+        assert_eq!(r.recv(), Some(21));
+        assert_eq!(r.recv(), None);
+    }
+
+    #[test]
+    fn close_wakes_both_sides() {
+        let (s, r) = sync_channel(1);
+        s.send(21).unwrap();
+        let t = thread::spawn(move || s.send(22));
+        thread::sleep(Duration::from_millis(50));
+        r.close();
+        assert_eq!(t.join().unwrap(), Err(SendError(22)));
+
+        let (s, mut r) = channel::<i32>();
+        let t = thread::spawn(move || r.recv());
+        thread::sleep(Duration::from_millis(50));
+        s.close();
+        assert_eq!(t.join().unwrap(), None);
+    }
+
+    #[test]
+    fn close_with_reason() {
+        let (s, mut r) = channel_with_reason::<i32, &str>();
+        s.close_with("shutting down");
+        // only the first close counts
+        s.close_with("again");
+        assert!(r.is_disconnected());
+        let closed = r.recv_with_reason().unwrap_err();
+        assert_eq!(closed.reason(), Some(&"shutting down"));
+
+        let (s, mut r) = sync_channel_with_reason::<i32, String>(1);
+        s.send(21).unwrap();
+        drop(s);
+        assert_eq!(r.recv_with_reason().unwrap(), 21);
+        assert!(r.recv_with_reason().unwrap_err().reason().is_none());
+
+        let (s, mut r) = channel::<i32>();
+        s.close();
+        assert!(r.recv_with_reason().unwrap_err().reason().is_none());
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats_count_across_receivers() {
+        let (s, mut r) = channel();
+        s.send_all(vec![21, 22, 23]).unwrap();
+        assert_eq!(r.recv(), Some(21));
+        let mut rc = r.clone();
         drop(r);
-        s.send(21);
+        assert_eq!(rc.recv(), Some(22));
+        assert_eq!(rc.recv(), Some(23));
+        let stats = s.stats();
+        assert_eq!(stats.sent, 3);
+        assert_eq!(stats.received, 3);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn stats() {
+        let (s, mut r) = sync_channel(4);
+        s.send_all(vec![21, 22, 23]).unwrap();
+        assert_eq!(r.recv(), Some(21));
+        let stats = r.stats();
+        assert_eq!(stats.sent, 3);
+        assert_eq!(stats.received, 1);
+        assert_eq!(stats.high_water_mark, 3);
+        assert_eq!(stats.recv_wait.count, 0);
+
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let mut got = Vec::new();
+            while got.len() < 5 {
+                r.recv_many(&mut got, 8);
+            }
+            got
+        });
+        s.send_all(vec![24, 25]).unwrap();
+        // the channel is full until the receiver wakes up
+        s.send(26).unwrap();
+        assert_eq!(t.join().unwrap(), vec![22, 23, 24, 25, 26]);
+        let stats = s.stats();
+        assert_eq!(stats.sent, 6);
+        assert_eq!(stats.received, 6);
+        assert_eq!(stats.high_water_mark, 4);
+        assert_eq!(stats.send_wait.count, 1);
+        assert!(stats.send_wait.total >= Duration::from_millis(40));
     }
 }
 
+// run with RUSTFLAGS="--cfg loom" LOOM_MAX_PREEMPTIONS=3 cargo test --release loom_tests,
+// the queue's spin loops make the unbounded search impractical
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::thread;
+    #[test]
+    fn last_sender_drop_wakes_receiver() {
+        loom::model(|| {
+            let (s, mut r) = channel();
+            let t = thread::spawn(move || {
+                s.send(21).unwrap();
+            });
+            assert_eq!(r.recv(), Some(21));
+            assert_eq!(r.recv(), None);
+            t.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn concurrent_senders_drop() {
+        loom::model(|| {
+            let (s, mut r) = channel();
+            let sc = s.clone();
+            let t1 = thread::spawn(move || s.send(21).unwrap());
+            let t2 = thread::spawn(move || sc.send(22).unwrap());
+            let mut got = vec![r.recv().unwrap(), r.recv().unwrap()];
+            got.sort();
+            assert_eq!(got, vec![21, 22]);
+            assert_eq!(r.recv(), None);
+            t1.join().unwrap();
+            t2.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn clone_while_dropping() {
+        loom::model(|| {
+            let (s, mut r) = channel();
+            let sc = s.clone();
+            let t1 = thread::spawn(move || drop(sc));
+            let t2 = thread::spawn(move || {
+                let s2 = s.clone();
+                drop(s);
+                s2.send(21).unwrap();
+            });
+            assert_eq!(r.recv(), Some(21));
+            assert_eq!(r.recv(), None);
+            t1.join().unwrap();
+            t2.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn recv_releases_capacity() {
+        loom::model(|| {
+            let (s, mut r) = sync_channel(2);
+            let t = thread::spawn(move || {
+                for i in 0..3 {
+                    s.send(i).unwrap();
+                }
+            });
+            // the blocked sender must be woken once a slot frees up, the
+            // third value also links in the queue's second block
+            assert_eq!(r.recv(), Some(0));
+            assert_eq!(r.recv(), Some(1));
+            assert_eq!(r.recv(), Some(2));
+            assert_eq!(r.recv(), None);
+            t.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn receiver_drop_wakes_blocked_sender() {
+        loom::model(|| {
+            let (s, r) = sync_channel(1);
+            s.send(21).unwrap();
+            let t = thread::spawn(move || s.send(22));
+            drop(r);
+            assert_eq!(t.join().unwrap(), Err(SendError(22)));
+        });
+    }
+
+    #[test]
+    fn rendezvous() {
+        loom::model(|| {
+            let (s, mut r) = sync_channel(0);
+            let t = thread::spawn(move || s.send(21));
+            assert_eq!(r.recv(), Some(21));
+            assert_eq!(t.join().unwrap(), Ok(()));
+        });
+    }
+
+    #[test]
+    fn upgrade_while_dropping() {
+        loom::model(|| {
+            let (s, mut r) = channel();
+            let weak = s.downgrade();
+            let t = thread::spawn(move || drop(s));
+            // either upgrades before the drop or the stream stays closed
+            if let Some(s) = weak.upgrade() {
+                s.send(21).unwrap();
+                drop(s);
+                assert_eq!(r.recv(), Some(21));
+            }
+            assert_eq!(r.recv(), None);
+            t.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn send_while_closing() {
+        loom::model(|| {
+            let (s, mut r) = channel();
+            let sc = s.clone();
+            let t = thread::spawn(move || sc.close());
+            // a value that got in before the close is still received
+            if s.send(21).is_ok() {
+                assert_eq!(r.recv(), Some(21));
+            }
+            assert_eq!(r.recv(), None);
+            t.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn receiver_drop_while_receiving() {
+        loom::model(|| {
+            let (s, mut r) = sync_channel(3);
+            s.send_all(vec![21, 22, 23]).unwrap();
+            assert_eq!(r.recv(), Some(21));
+            let mut rc = r.clone();
+            let t = thread::spawn(move || drop(r));
+            // r going away leaves the rest to rc
+            assert_eq!(rc.recv(), Some(22));
+            drop(s);
+            assert_eq!(rc.recv(), Some(23));
+            assert_eq!(rc.recv(), None);
+            t.join().unwrap();
+        });
+    }
+}
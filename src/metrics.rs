@@ -0,0 +1,231 @@
+//! Process-wide registry of named channels and an OpenMetrics exporter for it.
+//!
+//! Channels created with `channel_named` or `sync_channel_named` show up in
+//! `render_openmetrics` for as long as any of their handles is alive.
+
+use std::fmt::Write;
+use std::sync::{Mutex, PoisonError, Weak};
+
+use crate::{ChannelStats, WaitHistogram};
+
+// what the exporter needs from a channel, independent of its value type
+pub(crate) trait Probe: Send + Sync {
+    fn sample(&self) -> Sample;
+}
+
+pub(crate) struct Sample {
+    pub(crate) depth: usize,
+    pub(crate) senders: u32,
+    // no senders or no receivers left
+    pub(crate) disconnected: bool,
+    pub(crate) stats: ChannelStats,
+}
+
+struct Entry {
+    name: String,
+    channel: Weak<dyn Probe>,
+}
+
+// dead entries are pruned whenever the registry is touched
+static REGISTRY: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+pub(crate) fn register(name: &str, channel: Weak<dyn Probe>) {
+    let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    registry.retain(|e| e.channel.strong_count() > 0);
+    // two series with the same labels would be an invalid exposition
+    assert!(
+        registry.iter().all(|e| e.name != name),
+        "a channel named {:?} is already registered",
+        name
+    );
+    registry.push(Entry {
+        name: name.to_owned(),
+        channel,
+    });
+}
+
+/// Renders every live named channel in the OpenMetrics text format.
+///
+/// Each series is labelled with `channel="<name>"`.
+pub fn render_openmetrics() -> String {
+    let channels: Vec<_> = {
+        let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+        registry.retain(|e| e.channel.strong_count() > 0);
+        registry
+            .iter()
+            .filter_map(|e| Some((escape(&e.name), e.channel.upgrade()?)))
+            .collect()
+    };
+    // sampled outside the registry lock so a busy channel doesn't hold up others registering
+    let samples: Vec<_> = channels
+        .into_iter()
+        .map(|(name, channel)| (name, channel.sample()))
+        .collect();
+
+    let mut out = String::new();
+    family(&mut out, &samples, "depth", "gauge", "Values sent and not yet received.", |s| {
+        s.depth as u64
+    });
+    family(&mut out, &samples, "sent", "counter", "Values sent.", |s| s.stats.sent);
+    family(&mut out, &samples, "received", "counter", "Values handed out to receivers.", |s| {
+        s.stats.received
+    });
+    family(&mut out, &samples, "high_water_mark", "gauge", "Most values ever in flight at once.", |s| {
+        s.stats.high_water_mark as u64
+    });
+    family(&mut out, &samples, "senders", "gauge", "Live senders.", |s| s.senders as u64);
+    family(&mut out, &samples, "disconnected", "gauge", "1 once either side is gone.", |s| {
+        s.disconnected as u64
+    });
+    histogram(&mut out, &samples, "recv_wait_seconds", "Time receivers spent blocked.", |s| {
+        &s.stats.recv_wait
+    });
+    histogram(&mut out, &samples, "send_wait_seconds", "Time senders spent blocked.", |s| {
+        &s.stats.send_wait
+    });
+    out.push_str("# EOF\n");
+    out
+}
+
+fn family(
+    out: &mut String,
+    samples: &[(String, Sample)],
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl Fn(&Sample) -> u64,
+) {
+    let _ = writeln!(out, "# TYPE suez_channel_{} {}", name, kind);
+    let _ = writeln!(out, "# HELP suez_channel_{} {}", name, help);
+    // counter samples carry the _total suffix
+    let suffix = if kind == "counter" { "_total" } else { "" };
+    for (channel, s) in samples {
+        let _ = writeln!(out, "suez_channel_{}{}{{channel=\"{}\"}} {}", name, suffix, channel, value(s));
+    }
+}
+
+fn histogram(
+    out: &mut String,
+    samples: &[(String, Sample)],
+    name: &str,
+    help: &str,
+    value: impl Fn(&Sample) -> &WaitHistogram,
+) {
+    let _ = writeln!(out, "# TYPE suez_channel_{} histogram", name);
+    let _ = writeln!(out, "# HELP suez_channel_{} {}", name, help);
+    for (channel, s) in samples {
+        let h = value(s);
+        // exposition buckets are cumulative, ours are not
+        let mut count = 0;
+        for (i, n) in h.buckets.iter().enumerate() {
+            count += n;
+            let le = match WaitHistogram::BOUNDS.get(i) {
+                Some(bound) => bound.as_secs_f64().to_string(),
+                None => "+Inf".to_owned(),
+            };
+            let _ = writeln!(
+                out,
+                "suez_channel_{}_bucket{{channel=\"{}\",le=\"{}\"}} {}",
+                name, channel, le, count
+            );
+        }
+        let _ = writeln!(out, "suez_channel_{}_count{{channel=\"{}\"}} {}", name, channel, h.count);
+        let _ = writeln!(
+            out,
+            "suez_channel_{}_sum{{channel=\"{}\"}} {}",
+            name,
+            channel,
+            h.total.as_secs_f64()
+        );
+    }
+}
+
+fn escape(label: &str) -> String {
+    let mut out = String::with_capacity(label.len());
+    for c in label.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{channel_named, sync_channel_named};
+    use std::collections::HashMap;
+
+    // maps `name{labels}` to its value, checking every line is well formed
+    fn parse(text: &str) -> HashMap<String, f64> {
+        let mut lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.pop(), Some("# EOF"));
+        let mut series = HashMap::new();
+        for line in lines {
+            if line.starts_with("# TYPE ") || line.starts_with("# HELP ") {
+                continue;
+            }
+            let (key, value) = line.rsplit_once(' ').unwrap();
+            assert!(key.starts_with("suez_channel_") && key.ends_with('}'), "{}", line);
+            assert!(series.insert(key.to_owned(), value.parse().unwrap()).is_none(), "{}", line);
+        }
+        series
+    }
+
+    #[test]
+    fn render() {
+        let (s, mut r) = sync_channel_named("metrics-render", 4);
+        s.send_all(vec![21, 22, 23]).unwrap();
+        r.recv().unwrap();
+        let s2 = s.clone();
+        let series = parse(&render_openmetrics());
+        let get = |key: &str| series[&format!("suez_channel_{}", key)];
+        assert_eq!(get("depth{channel=\"metrics-render\"}"), 2.0);
+        assert_eq!(get("sent_total{channel=\"metrics-render\"}"), 3.0);
+        assert_eq!(get("received_total{channel=\"metrics-render\"}"), 1.0);
+        assert_eq!(get("high_water_mark{channel=\"metrics-render\"}"), 3.0);
+        assert_eq!(get("senders{channel=\"metrics-render\"}"), 2.0);
+        assert_eq!(get("disconnected{channel=\"metrics-render\"}"), 0.0);
+        assert_eq!(get("recv_wait_seconds_bucket{channel=\"metrics-render\",le=\"+Inf\"}"), 0.0);
+        assert_eq!(get("send_wait_seconds_count{channel=\"metrics-render\"}"), 0.0);
+
+        drop(s);
+        drop(s2);
+        let series = parse(&render_openmetrics());
+        assert_eq!(series["suez_channel_disconnected{channel=\"metrics-render\"}"], 1.0);
+        assert_eq!(series["suez_channel_senders{channel=\"metrics-render\"}"], 0.0);
+    }
+
+    #[test]
+    fn unregisters_on_last_drop() {
+        let (s, r) = channel_named::<i32>("metrics-unregister");
+        drop(s);
+        assert!(render_openmetrics().contains("channel=\"metrics-unregister\""));
+        drop(r);
+        assert!(!render_openmetrics().contains("channel=\"metrics-unregister\""));
+    }
+
+    #[test]
+    #[should_panic(expected = "a channel named \"metrics-duplicate\" is already registered")]
+    fn rejects_duplicate_names() {
+        let (_s, _r) = channel_named::<i32>("metrics-duplicate");
+        channel_named::<i32>("metrics-duplicate");
+    }
+
+    #[test]
+    fn reuses_names_of_dropped_channels() {
+        drop(channel_named::<i32>("metrics-reused"));
+        let (_s, _r) = channel_named::<i32>("metrics-reused");
+        assert!(render_openmetrics().contains("channel=\"metrics-reused\""));
+    }
+
+    #[test]
+    fn escapes_names() {
+        let (_s, _r) = channel_named::<i32>("metrics \"escaped\"\\\n");
+        let series = parse(&render_openmetrics());
+        assert!(series.contains_key("suez_channel_depth{channel=\"metrics \\\"escaped\\\"\\\\\\n\"}"));
+    }
+}
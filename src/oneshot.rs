@@ -0,0 +1,170 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+use crate::SendError;
+
+struct ChannelCtx<T> {
+    value: Option<T>,
+    sender_gone: bool,
+    receiver_gone: bool,
+}
+
+struct Shared<T> {
+    mu: Mutex<ChannelCtx<T>>,
+    cond: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, ChannelCtx<T>> {
+        self.mu.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends the value, giving it back in `SendError` if the receiver is gone.
+    pub fn send(self, value: T) -> Result<(), SendError<T>> {
+        let mut ctx = self.shared.lock();
+        if ctx.receiver_gone {
+            return Err(SendError(value));
+        }
+        ctx.value = Some(value);
+        drop(ctx);
+        self.shared.cond.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut ctx = self.shared.lock();
+        ctx.sender_gone = true;
+        drop(ctx);
+        self.shared.cond.notify_one();
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Blocks until the value arrives, or returns `Canceled` if the sender is dropped without sending.
+    pub fn recv(self) -> Result<T, Canceled> {
+        let mut ctx = self.shared.lock();
+        loop {
+            if let Some(value) = ctx.value.take() {
+                return Ok(value);
+            }
+            if ctx.sender_gone {
+                return Err(Canceled);
+            }
+            ctx = self.shared.cond.wait(ctx).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut ctx = self.shared.lock();
+        ctx.receiver_gone = true;
+        let value = ctx.value.take();
+        drop(ctx);
+        drop(value);
+    }
+}
+
+/// Creates a channel that carries exactly one value.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        mu: Mutex::new(ChannelCtx {
+            value: None,
+            sender_gone: false,
+            receiver_gone: false,
+        }),
+        cond: Condvar::new(),
+    });
+    let s = Sender {
+        shared: Arc::clone(&shared),
+    };
+    let r = Receiver {
+        shared: Arc::clone(&shared),
+    };
+    (s, r)
+}
+
+/// Error returned by `Receiver::recv` when the sender was dropped without sending.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Canceled;
+
+impl fmt::Display for Canceled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("oneshot canceled")
+    }
+}
+
+impl Error for Canceled {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    #[test]
+    fn send_recv() {
+        let (s, r) = channel();
+        s.send(21).unwrap();
+        assert_eq!(r.recv(), Ok(21));
+    }
+
+    #[test]
+    fn recv_blocks_until_send() {
+        let (s, r) = channel();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            s.send(21).unwrap();
+        });
+        assert_eq!(r.recv(), Ok(21));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn close_sending() {
+        let (s, r) = channel::<i32>();
+        drop(s);
+        assert_eq!(r.recv(), Err(Canceled));
+    }
+
+    #[test]
+    fn close_sending_wakes_receiver() {
+        let (s, r) = channel::<i32>();
+        let t = thread::spawn(move || r.recv());
+        thread::sleep(Duration::from_millis(50));
+        drop(s);
+        assert_eq!(t.join().unwrap(), Err(Canceled));
+    }
+
+    #[test]
+    fn close_receiving() {
+        let (s, r) = channel();
+        drop(r);
+        assert_eq!(s.send(21), Err(SendError(21)));
+    }
+
+    #[test]
+    fn survives_poisoned_lock() {
+        let (s, r) = channel();
+        let shared = Arc::clone(&s.shared);
+        let t = thread::spawn(move || {
+            let _ctx = shared.mu.lock().unwrap();
+            panic!("holder thread panicked");
+        });
+        assert!(t.join().is_err());
+        s.send(21).unwrap();
+        assert_eq!(r.recv(), Ok(21));
+    }
+}
@@ -0,0 +1,545 @@
+// The core channel's queue, an unbounded lock-free linked list of blocks
+// along the lines of crossbeam-channel's list flavor. Senders reserve
+// positions by moving `tail` forward, receivers claim them by moving `head`
+// forward, and nobody takes a lock on the way.
+//
+// Each position has a slot in some block except the last one of every lap,
+// which marks the end of the block. The sender reserving a block's last slot
+// links in the next block; the receiver claiming it moves `head` over, and
+// the block is freed once every slot in it has been read.
+
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::ptr;
+use std::sync::atomic::Ordering;
+
+use crate::sync::{fence, yield_now, AtomicPtr, AtomicUsize, UnsafeCell};
+
+// slot states, set in this order
+const WRITE: usize = 1;
+const READ: usize = 2;
+// the block is being freed, whoever reads the slot has to finish the job
+const DESTROY: usize = 4;
+
+// positions per block, the last one never holds a value, smaller under
+// loom so the models cross block boundaries
+#[cfg(not(loom))]
+const LAP: usize = 32;
+#[cfg(loom)]
+const LAP: usize = 4;
+const BLOCK_CAP: usize = LAP - 1;
+// positions are kept shifted left to make room for MARK_BIT
+const SHIFT: usize = 1;
+// in tail: nothing can be pushed anymore; in head: tail is in a later block,
+// so the queue can't be empty
+const MARK_BIT: usize = 1;
+
+struct Slot<T> {
+    value: UnsafeCell<MaybeUninit<T>>,
+    state: AtomicUsize,
+}
+
+impl<T> Slot<T> {
+    // a claimed slot may still be waiting for the sender that reserved it
+    fn wait_write(&self) {
+        let mut backoff = Backoff::new();
+        while self.state.load(Ordering::Acquire) & WRITE == 0 {
+            backoff.snooze();
+        }
+    }
+}
+
+struct Block<T> {
+    next: AtomicPtr<Block<T>>,
+    slots: [Slot<T>; BLOCK_CAP],
+}
+
+impl<T> Block<T> {
+    fn new() -> Box<Self> {
+        Box::new(Block {
+            next: AtomicPtr::new(ptr::null_mut()),
+            slots: std::array::from_fn(|_| Slot {
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+                state: AtomicUsize::new(0),
+            }),
+        })
+    }
+
+    fn wait_next(&self) -> *mut Block<T> {
+        let mut backoff = Backoff::new();
+        loop {
+            let next = self.next.load(Ordering::Acquire);
+            if !next.is_null() {
+                return next;
+            }
+            backoff.snooze();
+        }
+    }
+
+    // frees the block unless a slot from `start` on is still being read,
+    // in which case its reader calls this again once it's done
+    unsafe fn destroy(this: *mut Self, start: usize) {
+        // the last slot's reader is the one who started, no need to check it
+        for i in start..BLOCK_CAP - 1 {
+            let slot = &(*this).slots[i];
+            if slot.state.load(Ordering::Acquire) & READ == 0
+                && slot.state.fetch_or(DESTROY, Ordering::AcqRel) & READ == 0
+            {
+                return;
+            }
+        }
+        drop(Box::from_raw(this));
+    }
+}
+
+struct Position<T> {
+    index: AtomicUsize,
+    block: AtomicPtr<Block<T>>,
+}
+
+// keeps head and tail on separate cache lines, senders and receivers
+// would otherwise slow each other down on every operation
+#[repr(align(128))]
+struct Padded<T>(T);
+
+impl<T> Deref for Padded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// Why `Queue::pop` came back empty handed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum Pop {
+    Empty,
+    // empty and closed, nothing will ever be pushed again
+    Closed,
+}
+
+pub(crate) struct Queue<T> {
+    head: Padded<Position<T>>,
+    tail: Padded<Position<T>>,
+    _marker: PhantomData<T>,
+}
+
+// values only ever move through the queue, never get shared
+unsafe impl<T: Send> Send for Queue<T> {}
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<T> Queue<T> {
+    pub(crate) fn new() -> Self {
+        let block = Box::into_raw(Block::new());
+        Queue {
+            head: Padded(Position {
+                index: AtomicUsize::new(0),
+                block: AtomicPtr::new(block),
+            }),
+            tail: Padded(Position {
+                index: AtomicUsize::new(0),
+                block: AtomicPtr::new(block),
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Pushes `value`, returning its position, or gives it back once the queue is closed.
+    pub(crate) fn push(&self, value: T) -> Result<usize, T> {
+        match self.reserve(1) {
+            Some(reserved) => Ok(unsafe { reserved.write(std::iter::once(value)) }),
+            None => Err(value),
+        }
+    }
+
+    /// Pushes `values` at consecutive positions, returning the position of
+    /// the last one, or gives them back once the queue is closed.
+    pub(crate) fn push_all(&self, values: Vec<T>) -> Result<usize, Vec<T>> {
+        debug_assert!(!values.is_empty());
+        match self.reserve(values.len()) {
+            Some(reserved) => Ok(unsafe { reserved.write(values) }),
+            None => Err(values),
+        }
+    }
+
+    // moves tail past `n` positions, linking in new blocks for whatever
+    // doesn't fit into the current one
+    fn reserve(&self, n: usize) -> Option<Reserved<T>> {
+        let mut backoff = Backoff::new();
+        let mut tail = self.tail.index.load(Ordering::Acquire);
+        let mut block = self.tail.block.load(Ordering::Acquire);
+        // allocated before moving tail, so others don't wait on the allocator
+        let mut spare = Vec::new();
+        loop {
+            if tail & MARK_BIT != 0 {
+                return None;
+            }
+            let offset = (tail >> SHIFT) % LAP;
+            if offset == BLOCK_CAP {
+                // another sender is linking in the next block
+                backoff.snooze();
+                tail = self.tail.index.load(Ordering::Acquire);
+                block = self.tail.block.load(Ordering::Acquire);
+                continue;
+            }
+            // values that go into new blocks, if this reaches the end of the current one
+            let overflow = (offset + n).checked_sub(BLOCK_CAP);
+            let new_tail = match overflow {
+                // parks tail on the end of the block until the new ones are linked in
+                Some(_) => tail + ((BLOCK_CAP - offset) << SHIFT),
+                None => tail + (n << SHIFT),
+            };
+            let n_blocks = overflow.map_or(0, |m| m / BLOCK_CAP + 1);
+            while spare.len() < n_blocks {
+                spare.push(Block::new());
+            }
+            match self
+                .tail
+                .index
+                .compare_exchange_weak(tail, new_tail, Ordering::SeqCst, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    let blocks: Vec<_> = spare.drain(..n_blocks).map(Box::into_raw).collect();
+                    if let Some(m) = overflow {
+                        unsafe { self.link(block, &blocks, m) };
+                    }
+                    return Some(Reserved {
+                        block,
+                        offset,
+                        pos: tail >> SHIFT,
+                        blocks,
+                    });
+                }
+                Err(current) => {
+                    tail = current;
+                    block = self.tail.block.load(Ordering::Acquire);
+                    backoff.snooze();
+                }
+            }
+        }
+    }
+
+    // links `blocks` after `block` and moves tail past the `m` values going into them
+    unsafe fn link(&self, block: *mut Block<T>, blocks: &[*mut Block<T>], m: usize) {
+        for pair in blocks.windows(2) {
+            (*pair[0]).next.store(pair[1], Ordering::Release);
+        }
+        let last = blocks[blocks.len() - 1];
+        self.tail.block.store(last, Ordering::Release);
+        // an add rather than a store keeps MARK_BIT if the queue was closed meanwhile
+        let skip = 1 + (blocks.len() - 1) * LAP + m % BLOCK_CAP;
+        self.tail.index.fetch_add(skip << SHIFT, Ordering::Release);
+        (*block).next.store(blocks[0], Ordering::Release);
+    }
+
+    /// Takes the value at the head.
+    pub(crate) fn pop(&self) -> Result<T, Pop> {
+        let (block, offset) = self.claim(usize::MAX)?;
+        Ok(unsafe { Self::read(block, offset) })
+    }
+
+    /// Takes back the value at the head if it's at or before position `last`,
+    /// `None` once the head has moved past it.
+    ///
+    /// Only meant for a sender's own values, nothing before them may be left unclaimed.
+    pub(crate) fn reclaim(&self, last: usize) -> Option<T> {
+        let (block, offset) = self.claim(last).ok()?;
+        Some(unsafe { Self::read(block, offset) })
+    }
+
+    // moves head forward by one if it's at or before `last`, returning the claimed slot
+    fn claim(&self, last: usize) -> Result<(*mut Block<T>, usize), Pop> {
+        let mut backoff = Backoff::new();
+        let mut head = self.head.index.load(Ordering::Acquire);
+        let mut block = self.head.block.load(Ordering::Acquire);
+        loop {
+            let offset = (head >> SHIFT) % LAP;
+            if offset == BLOCK_CAP {
+                // another receiver is moving head over to the next block
+                backoff.snooze();
+                head = self.head.index.load(Ordering::Acquire);
+                block = self.head.block.load(Ordering::Acquire);
+                continue;
+            }
+            if head >> SHIFT > last {
+                return Err(Pop::Empty);
+            }
+            let mut new_head = head + (1 << SHIFT);
+            if new_head & MARK_BIT == 0 {
+                // pairs with the fence senders and parked receivers go through,
+                // see Shared::notify_receivers
+                fence(Ordering::SeqCst);
+                let tail = self.tail.index.load(Ordering::Relaxed);
+                if head >> SHIFT == tail >> SHIFT {
+                    return Err(if tail & MARK_BIT != 0 { Pop::Closed } else { Pop::Empty });
+                }
+                if (head >> SHIFT) / LAP != (tail >> SHIFT) / LAP {
+                    new_head |= MARK_BIT;
+                }
+            }
+            match self
+                .head
+                .index
+                .compare_exchange_weak(head, new_head, Ordering::SeqCst, Ordering::Acquire)
+            {
+                Ok(_) => unsafe {
+                    if offset + 1 == BLOCK_CAP {
+                        let next = (*block).wait_next();
+                        let mut next_index = (new_head & !MARK_BIT) + (1 << SHIFT);
+                        if !(*next).next.load(Ordering::Relaxed).is_null() {
+                            next_index |= MARK_BIT;
+                        }
+                        self.head.block.store(next, Ordering::Release);
+                        self.head.index.store(next_index, Ordering::Release);
+                    }
+                    return Ok((block, offset));
+                },
+                Err(current) => {
+                    head = current;
+                    block = self.head.block.load(Ordering::Acquire);
+                    backoff.snooze();
+                }
+            }
+        }
+    }
+
+    unsafe fn read(block: *mut Block<T>, offset: usize) -> T {
+        let slot = &(*block).slots[offset];
+        slot.wait_write();
+        let value = slot.value.with(|p| (*p).assume_init_read());
+        // the block may be gone as soon as READ is set
+        if offset + 1 == BLOCK_CAP {
+            Block::destroy(block, 0);
+        } else if slot.state.fetch_or(READ, Ordering::AcqRel) & DESTROY != 0 {
+            Block::destroy(block, offset + 1);
+        }
+        value
+    }
+
+    /// Position of the next value to be claimed, every value before it has been.
+    pub(crate) fn head_pos(&self) -> usize {
+        self.head.index.load(Ordering::SeqCst) >> SHIFT
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        let head = self.head.index.load(Ordering::SeqCst);
+        let tail = self.tail.index.load(Ordering::SeqCst);
+        head >> SHIFT == tail >> SHIFT
+    }
+
+    /// Stops any further pushes, returns false if it was already closed.
+    pub(crate) fn close(&self) -> bool {
+        self.tail.index.fetch_or(MARK_BIT, Ordering::SeqCst) & MARK_BIT == 0
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.tail.index.load(Ordering::SeqCst) & MARK_BIT != 0
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        // nobody else is left, every reserved slot has been written
+        let mut head = self.head.index.load(Ordering::Relaxed) & !MARK_BIT;
+        let tail = self.tail.index.load(Ordering::Relaxed) & !MARK_BIT;
+        let mut block = self.head.block.load(Ordering::Relaxed);
+        unsafe {
+            while head != tail {
+                let offset = (head >> SHIFT) % LAP;
+                if offset < BLOCK_CAP {
+                    (*block).slots[offset].value.with_mut(|p| (*p).assume_init_drop());
+                } else {
+                    let next = (*block).next.load(Ordering::Relaxed);
+                    drop(Box::from_raw(block));
+                    block = next;
+                }
+                head += 1 << SHIFT;
+            }
+            drop(Box::from_raw(block));
+        }
+    }
+}
+
+// positions moved past by a push, waiting for their values
+struct Reserved<T> {
+    block: *mut Block<T>,
+    offset: usize,
+    pos: usize,
+    // linked in after `block`, in order
+    blocks: Vec<*mut Block<T>>,
+}
+
+impl<T> Reserved<T> {
+    // writes exactly as many values as were reserved, returns the position of the last one
+    unsafe fn write(self, values: impl IntoIterator<Item = T>) -> usize {
+        let Reserved {
+            mut block,
+            mut offset,
+            mut pos,
+            blocks,
+        } = self;
+        // a block may be freed as soon as its last slot is written and read,
+        // so the next one comes from `blocks` rather than from `block.next`
+        let mut blocks = blocks.into_iter();
+        let mut last = pos;
+        for value in values {
+            if offset == BLOCK_CAP {
+                block = blocks.next().unwrap();
+                offset = 0;
+                pos += 1;
+            }
+            let slot = &(*block).slots[offset];
+            slot.value.with_mut(|p| p.write(MaybeUninit::new(value)));
+            slot.state.fetch_or(WRITE, Ordering::Release);
+            last = pos;
+            offset += 1;
+            pos += 1;
+        }
+        last
+    }
+}
+
+// spins a little while others finish what they started, then yields
+struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Backoff { step: 0 }
+    }
+
+    fn snooze(&mut self) {
+        // loom only moves on to another thread at a yield
+        if cfg!(loom) || self.step > 6 {
+            yield_now();
+        } else {
+            for _ in 0..1 << self.step {
+                std::hint::spin_loop();
+            }
+            self.step += 1;
+        }
+    }
+}
+
+// these are also meant for `cargo +nightly miri test`, which checks the
+// block handling above for leaks, use-after-frees and data races
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    #[test]
+    fn push_pop_across_blocks() {
+        let q = Queue::new();
+        for i in 0..100 {
+            q.push(i).unwrap();
+        }
+        for i in 0..100 {
+            assert_eq!(q.pop(), Ok(i));
+        }
+        assert_eq!(q.pop(), Err(Pop::Empty));
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn push_all_spans_blocks() {
+        let q = Queue::new();
+        q.push(0).unwrap();
+        let last = q.push_all((1..=70).collect()).unwrap();
+        // one position per value plus the end of every block crossed
+        assert_eq!(last, 72);
+        // exactly fills up the block it ends in
+        q.push_all((71..=92).collect()).unwrap();
+        q.push(93).unwrap();
+        for i in 0..=93 {
+            assert_eq!(q.pop(), Ok(i));
+        }
+        assert_eq!(q.pop(), Err(Pop::Empty));
+    }
+
+    #[test]
+    fn close() {
+        let q = Queue::new();
+        q.push(21).unwrap();
+        assert!(q.close());
+        assert!(!q.close());
+        assert!(q.is_closed());
+        assert_eq!(q.push(22), Err(22));
+        assert_eq!(q.push_all(vec![23]), Err(vec![23]));
+        assert_eq!(q.pop(), Ok(21));
+        assert_eq!(q.pop(), Err(Pop::Closed));
+    }
+
+    #[test]
+    fn reclaim() {
+        let q = Queue::new();
+        let pos = q.push(21).unwrap();
+        assert_eq!(q.reclaim(pos), Some(21));
+        let pos = q.push(22).unwrap();
+        assert_eq!(q.pop(), Ok(22));
+        assert_eq!(q.reclaim(pos), None);
+        let last = q.push_all((0..40).collect()).unwrap();
+        assert_eq!(q.pop(), Ok(0));
+        let rest: Vec<_> = std::iter::from_fn(|| q.reclaim(last)).collect();
+        assert_eq!(rest, (1..40).collect::<Vec<_>>());
+        assert_eq!(q.head_pos(), last + 1);
+    }
+
+    #[test]
+    fn drops_what_is_left() {
+        let value = Arc::new(21);
+        let q = Queue::new();
+        for _ in 0..40 {
+            q.push(Arc::clone(&value)).unwrap();
+        }
+        for _ in 0..35 {
+            q.pop().unwrap();
+        }
+        drop(q);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn concurrent() {
+        let n: usize = if cfg!(miri) { 40 } else { 10_000 };
+        let q = Arc::new(Queue::new());
+        let senders: Vec<_> = (0..4)
+            .map(|i| {
+                let q = Arc::clone(&q);
+                thread::spawn(move || {
+                    for j in 0..n {
+                        if j % 10 == 0 {
+                            q.push_all(vec![1; 5]).unwrap();
+                        } else {
+                            q.push(i).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let receivers: Vec<_> = (0..2)
+            .map(|_| {
+                let q = Arc::clone(&q);
+                thread::spawn(move || {
+                    let mut total = 0;
+                    loop {
+                        match q.pop() {
+                            Ok(value) => total += value,
+                            Err(Pop::Closed) => return total,
+                            Err(Pop::Empty) => thread::yield_now(),
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in senders {
+            t.join().unwrap();
+        }
+        q.close();
+        let total: usize = receivers.into_iter().map(|t| t.join().unwrap()).sum();
+        let batches = n.div_ceil(10);
+        assert_eq!(total, (0..4).map(|i| i * (n - batches) + 5 * batches).sum::<usize>());
+    }
+}
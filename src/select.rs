@@ -0,0 +1,318 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::task::{Wake, Waker};
+use std::time::{Duration, Instant};
+
+use crate::Receiver;
+
+// implemented by every handle a `Select` can wait on
+pub(crate) trait Selectable {
+    // whether an operation on the handle would complete without blocking
+    fn is_ready(&self) -> bool;
+    // returns true if the handle is ready, otherwise leaves `waker`
+    // with the channel to be woken once it might be
+    fn register(&self, waker: &Waker) -> bool;
+    fn unregister(&self, waker: &Waker);
+}
+
+// wakes the thread blocked in `Select`
+struct Signal {
+    mu: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Signal {
+    // returns false if the deadline passed before anyone woke us
+    fn wait(&self, deadline: Option<Instant>) -> bool {
+        let mut woken = self.mu.lock().unwrap_or_else(PoisonError::into_inner);
+        while !*woken {
+            match deadline {
+                None => woken = self.cond.wait(woken).unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    woken = self.cond.wait_timeout(woken, deadline - now).unwrap_or_else(PoisonError::into_inner).0;
+                }
+            }
+        }
+        *woken = false;
+        true
+    }
+}
+
+impl Wake for Signal {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        *self.mu.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        self.cond.notify_one();
+    }
+}
+
+/// Waits on several receivers at once and reports which one is ready.
+///
+/// A receiver is ready when `try_recv` on it would not return `Empty`. Operations
+/// are checked in the order they were added, so earlier ones win ties.
+pub struct Select<'a> {
+    handles: Vec<&'a dyn Selectable>,
+}
+
+impl<'a> Select<'a> {
+    pub fn new() -> Self {
+        Self {
+            handles: Vec::new(),
+        }
+    }
+
+    /// Adds a receive operation, returning its index.
+    pub fn recv<T, E>(&mut self, r: &'a Receiver<T, E>) -> usize {
+        self.handles.push(r);
+        self.handles.len() - 1
+    }
+
+    /// Returns the index of a ready operation without blocking.
+    pub fn try_ready(&mut self) -> Result<usize, TryReadyError> {
+        let ready = self.handles.iter().position(|h| h.is_ready());
+        ready.ok_or(TryReadyError)
+    }
+
+    /// Blocks until one of the operations is ready and returns its index.
+    ///
+    /// Panics if no operations were added.
+    pub fn ready(&mut self) -> usize {
+        self.wait(None).unwrap()
+    }
+
+    /// Like `ready`, but gives up after `timeout`.
+    pub fn ready_timeout(&mut self, timeout: Duration) -> Result<usize, ReadyTimeoutError> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.ready_deadline(deadline),
+            None => Ok(self.ready()),
+        }
+    }
+
+    /// Like `ready`, but gives up once `deadline` has passed.
+    pub fn ready_deadline(&mut self, deadline: Instant) -> Result<usize, ReadyTimeoutError> {
+        self.wait(Some(deadline)).ok_or(ReadyTimeoutError)
+    }
+
+    fn wait(&mut self, deadline: Option<Instant>) -> Option<usize> {
+        assert!(!self.handles.is_empty(), "no operations to select on");
+        let signal = Arc::new(Signal {
+            mu: Mutex::new(false),
+            cond: Condvar::new(),
+        });
+        let waker = Waker::from(signal.clone());
+        loop {
+            let ready = self.handles.iter().position(|h| h.register(&waker));
+            if ready.is_none() && signal.wait(deadline) {
+                // woken by one of the channels, go around and find out which
+                continue;
+            }
+            for h in &self.handles {
+                h.unregister(&waker);
+            }
+            return ready;
+        }
+    }
+}
+
+impl Default for Select<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Blocks on several receivers and runs the arm of the first one with a message.
+///
+/// Each `recv` arm binds the result of receiving, `None` meaning that channel is
+/// disconnected. An optional trailing `default(timeout)` arm runs if nothing is
+/// ready in time.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// let (data_s, mut data) = suez::channel();
+/// let (_ctl_s, mut ctl) = suez::channel::<()>();
+/// data_s.send(21).unwrap();
+/// let got = suez::select! {
+///     recv(ctl) -> _msg => None,
+///     recv(data) -> msg => msg,
+///     default(Duration::from_secs(1)) => None,
+/// };
+/// assert_eq!(got, Some(21));
+/// ```
+#[macro_export]
+macro_rules! select {
+    ($(recv($rx:expr) -> $res:pat => $body:expr),+ $(,)?) => {
+        $crate::select!(@run None, $(recv($rx) -> $res => $body,)+ default => unreachable!())
+    };
+    ($(recv($rx:expr) -> $res:pat => $body:expr,)+ default($timeout:expr) => $default:expr $(,)?) => {
+        $crate::select!(@run ::std::time::Instant::now().checked_add($timeout), $(recv($rx) -> $res => $body,)+ default => $default)
+    };
+    (@run $deadline:expr, $(recv($rx:expr) -> $res:pat => $body:expr,)+ default => $default:expr) => {{
+        let __deadline: ::std::option::Option<::std::time::Instant> = $deadline;
+        #[allow(unreachable_code, clippy::diverging_sub_expression)]
+        let __out = loop {
+            let __ready = {
+                let mut __sel = $crate::Select::new();
+                $(
+                    __sel.recv(&$rx);
+                )+
+                match __deadline {
+                    ::std::option::Option::Some(__deadline) => __sel.ready_deadline(__deadline).ok(),
+                    ::std::option::Option::None => ::std::option::Option::Some(__sel.ready()),
+                }
+            };
+            let __index = match __ready {
+                ::std::option::Option::Some(__index) => __index,
+                ::std::option::Option::None => break $default,
+            };
+            let __i = 0usize;
+            $(
+                if __index == __i {
+                    let __msg = match $rx.try_recv() {
+                        ::std::result::Result::Ok(__value) => ::std::option::Option::Some(__value),
+                        ::std::result::Result::Err($crate::TryRecvError::Disconnected) => ::std::option::Option::None,
+                        // another receiver got there first, wait again
+                        ::std::result::Result::Err($crate::TryRecvError::Empty) => continue,
+                    };
+                    let $res = __msg;
+                    break $body;
+                }
+                let __i = __i + 1;
+            )+
+            let _ = __i;
+        };
+        __out
+    }};
+}
+
+/// Error returned by `Select::try_ready` when nothing is ready.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TryReadyError;
+
+impl fmt::Display for TryReadyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("no operation is ready")
+    }
+}
+
+impl Error for TryReadyError {}
+
+/// Error returned by `Select::ready_timeout` and `Select::ready_deadline`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ReadyTimeoutError;
+
+impl fmt::Display for ReadyTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("timed out waiting for an operation")
+    }
+}
+
+impl Error for ReadyTimeoutError {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::{channel, sync_channel};
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    #[test]
+    fn try_ready() {
+        let (_s1, r1) = channel::<i32>();
+        let (s2, r2) = channel();
+        let mut sel = Select::new();
+        sel.recv(&r1);
+        sel.recv(&r2);
+        assert_eq!(sel.try_ready(), Err(TryReadyError));
+        // nothing is left registered with the channels
+        assert_eq!(r1.shared.recv_watch.load(Ordering::SeqCst), 0);
+        s2.send(21).unwrap();
+        assert_eq!(sel.try_ready(), Ok(1));
+    }
+
+    #[test]
+    fn ready_blocks_until_send() {
+        let (_s1, r1) = channel::<i32>();
+        let (s2, mut r2) = channel();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            s2.send(21).unwrap();
+        });
+        let mut sel = Select::new();
+        sel.recv(&r1);
+        sel.recv(&r2);
+        assert_eq!(sel.ready(), 1);
+        assert_eq!(r2.try_recv(), Ok(21));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn ready_on_disconnect() {
+        let (s, r) = sync_channel::<i32>(1);
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(s);
+        });
+        let mut sel = Select::new();
+        sel.recv(&r);
+        assert_eq!(sel.ready(), 0);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn ready_timeout() {
+        let (_s, r) = channel::<i32>();
+        let mut sel = Select::new();
+        sel.recv(&r);
+        assert_eq!(sel.ready_timeout(Duration::from_millis(50)), Err(ReadyTimeoutError));
+    }
+
+    // Miri gives cloned wakers fresh vtables, so will_wake never matches there
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn unregisters_wakers() {
+        let (_s1, r1) = channel::<i32>();
+        let (s2, r2) = channel();
+        s2.send(21).unwrap();
+        let mut sel = Select::new();
+        sel.recv(&r1);
+        sel.recv(&r2);
+        assert_eq!(sel.ready(), 1);
+        assert!(r1.shared.mu.lock().unwrap().wakers.is_empty());
+    }
+
+    #[test]
+    fn select_macro() {
+        let (s1, mut r1) = channel();
+        let (s2, mut r2) = channel::<i32>();
+        s1.send(21).unwrap();
+        drop(s2);
+        let got = crate::select! {
+            recv(r1) -> msg => msg,
+            recv(r2) -> _msg => panic!("r2 was ready first"),
+        };
+        assert_eq!(got, Some(21));
+        let got = crate::select! {
+            recv(r1) -> _msg => panic!("r1 is empty"),
+            recv(r2) -> msg => msg,
+        };
+        assert_eq!(got, None);
+    }
+
+    #[test]
+    fn select_macro_default() {
+        let (_s, mut r) = channel::<i32>();
+        let got = crate::select! {
+            recv(r) -> msg => msg,
+            default(Duration::from_millis(50)) => Some(0),
+        };
+        assert_eq!(got, Some(0));
+    }
+}
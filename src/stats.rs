@@ -0,0 +1,99 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Snapshot of a channel's counters, see `Sender::stats` and `Receiver::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// Values sent over the channel's lifetime.
+    pub sent: u64,
+    /// Values handed out to receivers over the channel's lifetime.
+    pub received: u64,
+    /// Most values that were ever in flight at once.
+    pub high_water_mark: usize,
+    /// Time receiving threads spent blocked waiting for a value.
+    pub recv_wait: WaitHistogram,
+    /// Time sending threads spent blocked waiting for capacity or for pickup.
+    pub send_wait: WaitHistogram,
+}
+
+/// How long blocked threads waited, bucketed by `WaitHistogram::BOUNDS`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WaitHistogram {
+    /// `buckets[i]` counts waits longer than `BOUNDS[i - 1]` and at most `BOUNDS[i]`,
+    /// the last bucket counts everything longer than the last bound.
+    pub buckets: [u64; 7],
+    /// Number of waits.
+    pub count: u64,
+    /// Sum of all waits.
+    pub total: Duration,
+}
+
+impl WaitHistogram {
+    /// Upper bounds of all but the last bucket.
+    pub const BOUNDS: [Duration; 6] = [
+        Duration::from_micros(10),
+        Duration::from_micros(100),
+        Duration::from_millis(1),
+        Duration::from_millis(10),
+        Duration::from_millis(100),
+        Duration::from_secs(1),
+    ];
+
+    pub(crate) fn record(&mut self, wait: Duration) {
+        let i = Self::BOUNDS.iter().position(|&b| wait <= b).unwrap_or(Self::BOUNDS.len());
+        self.buckets[i] += 1;
+        self.count += 1;
+        self.total += wait;
+    }
+}
+
+// kept in Shared, updated by senders and receivers without taking the lock
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) sent: AtomicU64,
+    pub(crate) received: AtomicU64,
+    pub(crate) high_water_mark: AtomicUsize,
+}
+
+impl Counters {
+    pub(crate) fn pushed(&self, n: usize) {
+        self.sent.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    // a rendezvous value its sender took back
+    pub(crate) fn unpushed(&self, n: usize) {
+        self.sent.fetch_sub(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn taken(&self, n: usize) {
+        self.received.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reached(&self, len: usize) {
+        self.high_water_mark.fetch_max(len, Ordering::Relaxed);
+    }
+}
+
+// kept in ChannelCtx, waits are recorded under the lock anyway
+#[derive(Default)]
+pub(crate) struct Recorder {
+    pub(crate) recv_wait: WaitHistogram,
+    pub(crate) send_wait: WaitHistogram,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn buckets() {
+        let mut h = WaitHistogram::default();
+        h.record(Duration::ZERO);
+        h.record(Duration::from_micros(10));
+        h.record(Duration::from_micros(11));
+        h.record(Duration::from_millis(5));
+        h.record(Duration::from_secs(5));
+        assert_eq!(h.buckets, [2, 1, 0, 1, 0, 0, 1]);
+        assert_eq!(h.count, 5);
+        assert_eq!(h.total, Duration::from_micros(5_005_021));
+    }
+}
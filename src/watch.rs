@@ -0,0 +1,259 @@
+use std::error::Error;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+struct ChannelCtx<T> {
+    // `Ref`s hold clones, only ever taken and dropped under the lock
+    value: Arc<T>,
+    // bumped on every send
+    version: u64,
+    sender_gone: bool,
+}
+
+struct Shared<T> {
+    mu: Mutex<ChannelCtx<T>>,
+    cond: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, ChannelCtx<T>> {
+        self.mu.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Replaces the current value, returning the old one, and wakes every receiver.
+    pub fn send_replace(&self, value: T) -> T {
+        let mut ctx = self.shared.lock();
+        let mut old = std::mem::replace(&mut ctx.value, Arc::new(value));
+        ctx.version += 1;
+        self.shared.cond.notify_all();
+        // wait for the `Ref`s still pointing at the old value
+        loop {
+            match Arc::try_unwrap(old) {
+                Ok(old) => return old,
+                Err(shared) => old = shared,
+            }
+            ctx = self.shared.cond.wait(ctx).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Creates a receiver that treats the current value as already seen.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let ctx = self.shared.lock();
+        let seen = ctx.version;
+        drop(ctx);
+        Receiver {
+            shared: Arc::clone(&self.shared),
+            seen,
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut ctx = self.shared.lock();
+        ctx.sender_gone = true;
+        drop(ctx);
+        self.shared.cond.notify_all();
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    // version of the value this receiver last saw
+    seen: u64,
+}
+
+impl<T> Receiver<T> {
+    /// Borrows the latest value without marking it as seen.
+    ///
+    /// The sender is blocked for as long as the returned `Ref` is alive.
+    pub fn borrow(&self) -> Ref<'_, T> {
+        let ctx = self.shared.lock();
+        Ref {
+            value: Some(Arc::clone(&ctx.value)),
+            shared: &self.shared,
+        }
+    }
+
+    /// Borrows the latest value and marks it as seen.
+    pub fn borrow_and_update(&mut self) -> Ref<'_, T> {
+        let ctx = self.shared.lock();
+        self.seen = ctx.version;
+        Ref {
+            value: Some(Arc::clone(&ctx.value)),
+            shared: &self.shared,
+        }
+    }
+
+    /// Blocks until a value newer than the last seen one is sent, and marks it as seen.
+    ///
+    /// Returns `RecvError` once the sender is gone and nothing new is left.
+    pub fn changed(&mut self) -> Result<(), RecvError> {
+        let mut ctx = self.shared.lock();
+        loop {
+            if ctx.version != self.seen {
+                self.seen = ctx.version;
+                return Ok(());
+            }
+            if ctx.sender_gone {
+                return Err(RecvError);
+            }
+            ctx = self.shared.cond.wait(ctx).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+            seen: self.seen,
+        }
+    }
+}
+
+/// Borrowed latest value of a watch channel, see `Receiver::borrow`.
+pub struct Ref<'a, T> {
+    // only `None` while dropping
+    value: Option<Arc<T>>,
+    shared: &'a Shared<T>,
+}
+
+impl<T> Deref for Ref<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.as_deref().unwrap()
+    }
+}
+
+impl<T> Drop for Ref<'_, T> {
+    fn drop(&mut self) {
+        let ctx = self.shared.lock();
+        drop(self.value.take());
+        drop(ctx);
+        self.shared.cond.notify_all();
+    }
+}
+
+/// Creates a watch channel holding `init` until the first send.
+pub fn channel<T>(init: T) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        mu: Mutex::new(ChannelCtx {
+            value: Arc::new(init),
+            version: 0,
+            sender_gone: false,
+        }),
+        cond: Condvar::new(),
+    });
+    let s = Sender {
+        shared: Arc::clone(&shared),
+    };
+    let r = Receiver {
+        shared: Arc::clone(&shared),
+        seen: 0,
+    };
+    (s, r)
+}
+
+/// Error returned by `Receiver::changed` when the sender is gone.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("watch sender dropped")
+    }
+}
+
+impl Error for RecvError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    #[test]
+    fn borrow_latest() {
+        let (s, r) = channel(21);
+        assert_eq!(*r.borrow(), 21);
+        assert_eq!(s.send_replace(22), 21);
+        assert_eq!(s.send_replace(23), 22);
+        assert_eq!(*r.borrow(), 23);
+    }
+
+    #[test]
+    fn changed() {
+        let (s, mut r) = channel(21);
+        s.send_replace(22);
+        s.send_replace(23);
+        assert_eq!(r.changed(), Ok(()));
+        assert_eq!(*r.borrow(), 23);
+        drop(s);
+        assert_eq!(r.changed(), Err(RecvError));
+    }
+
+    #[test]
+    fn changed_blocks_until_send() {
+        let (s, r) = channel(21);
+        let threads: Vec<_> = (0..3)
+            .map(|_| {
+                let mut r = r.clone();
+                thread::spawn(move || {
+                    r.changed().unwrap();
+                    *r.borrow()
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(50));
+        s.send_replace(22);
+        for t in threads {
+            assert_eq!(t.join().unwrap(), 22);
+        }
+    }
+
+    #[test]
+    fn subscribe_and_borrow_and_update() {
+        let (s, mut r1) = channel(21);
+        s.send_replace(22);
+        let mut r2 = s.subscribe();
+        assert_eq!(*r1.borrow_and_update(), 22);
+        drop(s);
+        assert_eq!(r1.changed(), Err(RecvError));
+        assert_eq!(r2.changed(), Err(RecvError));
+    }
+
+    #[test]
+    fn send_replace_waits_for_ref() {
+        let (s, r) = channel(21);
+        let old = r.borrow();
+        let t = thread::spawn(move || s.send_replace(22));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!t.is_finished());
+        assert_eq!(*old, 21);
+        drop(old);
+        assert_eq!(t.join().unwrap(), 21);
+        assert_eq!(*r.borrow(), 22);
+    }
+
+    #[test]
+    fn survives_panic_while_borrowed() {
+        let (s, r) = channel(21);
+        let t = thread::spawn(move || {
+            let _value = r.borrow();
+            panic!("holder thread panicked");
+        });
+        assert!(t.join().is_err());
+        let mut r = s.subscribe();
+        assert_eq!(s.send_replace(22), 21);
+        assert_eq!(r.changed(), Ok(()));
+        assert_eq!(*r.borrow(), 22);
+    }
+}